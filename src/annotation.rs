@@ -0,0 +1,628 @@
+use crate::client::PointerInput;
+use crate::shared::{Annotation, Callout, MousePosition, Shape, ShapeKind, ShapeUpdate, StrokeStyle};
+
+// A tutor could otherwise keep drawing until the student runs out of memory.
+const MAX_MARKS : usize = 4096;
+const MAX_POINTS : usize = 100_000;
+// How many changes a tutor can step back through.
+const MAX_HISTORY : usize = 256;
+// Callouts are for a word or two, not essays.
+const MAX_CALLOUT_LENGTH : usize = 200;
+// Labels are filled in every frame, so they get a tighter limit than other marks.
+const MAX_CALLOUTS : usize = 64;
+const MAX_TEXT_SIZE : u8 = 96;
+// How close to a shape a click has to be to grab it, across the screen.
+const GRAB_MARGIN : f32 = 0.01;
+
+pub struct Stroke {
+    pub id : u32,
+    pub style : StrokeStyle,
+    pub points : Vec<MousePosition>,
+}
+
+// Drawn in the order they were made, later marks on top.
+pub enum Mark {
+    Stroke(Stroke),
+    Shape(Shape),
+    Callout(Callout),
+}
+
+// Everything one tutor has drawn, kept until they clear it.
+pub struct Ink {
+    pub marks : Vec<Mark>,
+    // Bumped on every change, so renderers know to redraw.
+    pub version : u32,
+    undo : Vec<Change>,
+    redo : Vec<Change>,
+    // The tutor let go since the last change, so it can't be merged into.
+    done : bool,
+}
+
+// One step through a tutor's history, holding whatever it takes to go back the other way.
+enum Change {
+    // The mark with this id went on top.
+    Added(u32),
+    // The mark with the same id used to look like this.
+    Edited(Mark),
+    // This mark was taken out from here.
+    Removed(usize, Mark),
+    // Everything that was on screen before a clear.
+    Cleared(Vec<Mark>),
+    // A clear was undone, redoing it wipes them again.
+    Restored,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Tool {
+    Pen,
+    Shape(ShapeKind),
+    Text,
+}
+
+// The tutor's side of drawing, turns drags into annotations and keeps a copy of what the student has so shapes can be grabbed again.
+pub struct Sketch {
+    tool : Tool,
+    style : StrokeStyle,
+    text_size : u8,
+    next_id : u32,
+    layer : Ink,
+    drag : Option<Drag>,
+    // The callout keys are going into.
+    typing : Option<Callout>,
+}
+
+enum Drag {
+    // Nothing's been sent for it yet.
+    Starting(MousePosition),
+    Stroke(u32, MousePosition),
+    Shape(Shape),
+    Move { original : Shape, grabbed : MousePosition, moved : Shape },
+}
+
+impl Mark {
+    pub fn id(&self) -> u32 {
+        match self {
+            Mark::Stroke(stroke) => stroke.id,
+            Mark::Shape(shape) => shape.id,
+            Mark::Callout(callout) => callout.id,
+        }
+    }
+}
+
+impl Ink {
+    pub fn new() -> Ink {
+        Ink { marks : Vec::new(), version : 0, undo : Vec::new(), redo : Vec::new(), done : false }
+    }
+
+    // Anything left to show or bring back, a tutor's layer is only forgotten once this is false.
+    pub fn has_history(&self) -> bool {
+        !self.marks.is_empty() || !self.undo.is_empty() || !self.redo.is_empty()
+    }
+
+    pub fn apply(&mut self, annotation : Annotation) {
+        match annotation {
+            Annotation::Begin { stroke, style, point } => {
+                if self.marks.len() >= MAX_MARKS || self.points() >= MAX_POINTS {
+                    return;
+                }
+                self.marks.push(Mark::Stroke(Stroke { id : stroke, style, points : vec![point] }));
+                self.record(Change::Added(stroke));
+            }
+            Annotation::Extend { stroke, points } => {
+                let room = MAX_POINTS.saturating_sub(self.points());
+                // Only the stroke being drawn ever grows, anything else is a stray packet.
+                let last = match self.marks.last_mut() {
+                    Some(Mark::Stroke(last)) if last.id == stroke => last,
+                    _ => return,
+                };
+                last.points.extend(points.into_iter().take(room));
+            }
+            Annotation::Clear => {
+                if self.marks.is_empty() {
+                    return;
+                }
+                let marks = std::mem::take(&mut self.marks);
+                self.record(Change::Cleared(marks));
+            }
+            Annotation::Undo => {
+                let change = match self.undo.pop() {
+                    Some(change) => change,
+                    None => return,
+                };
+                let reverse = self.revert(change);
+                self.redo.extend(reverse);
+                self.done = true;
+            }
+            Annotation::Redo => {
+                let change = match self.redo.pop() {
+                    Some(change) => change,
+                    None => return,
+                };
+                let reverse = self.revert(change);
+                self.undo.extend(reverse);
+                self.done = true;
+            }
+            Annotation::Done => {
+                self.done = true;
+                return;
+            }
+            // Nobody gets to undo this one.
+            Annotation::ClearAll => {
+                self.marks.clear();
+                self.undo.clear();
+                self.redo.clear();
+            }
+        }
+        self.version = self.version.wrapping_add(1);
+    }
+
+    pub fn shape(&mut self, update : ShapeUpdate) {
+        match update {
+            ShapeUpdate::Place(shape) => {
+                if !self.place(Mark::Shape(shape)) {
+                    return;
+                }
+            }
+            ShapeUpdate::Remove(id) => {
+                let index = match self.find(id) {
+                    Some(index) => index,
+                    None => return,
+                };
+                let mark = self.marks.remove(index);
+                self.record(Change::Removed(index, mark));
+            }
+        }
+        self.version = self.version.wrapping_add(1);
+    }
+
+    pub fn callout(&mut self, mut callout : Callout) {
+        if let Some((cut, _)) = callout.text.char_indices().nth(MAX_CALLOUT_LENGTH) {
+            callout.text.truncate(cut);
+        }
+        callout.size = callout.size.min(MAX_TEXT_SIZE);
+        if self.find(callout.id).is_none() && self.marks.iter().filter(|mark| matches!(mark, Mark::Callout(_))).count() >= MAX_CALLOUTS {
+            return;
+        }
+        if self.place(Mark::Callout(callout)) {
+            self.version = self.version.wrapping_add(1);
+        }
+    }
+
+    // Puts a mark on top, or swaps out the one with the same id where it is.
+    fn place(&mut self, mark : Mark) -> bool {
+        let id = mark.id();
+        match self.find(id) {
+            Some(index) => {
+                let old = std::mem::replace(&mut self.marks[index], mark);
+                // Dragging a shape or typing a label sends a stream of updates, they undo as one.
+                if !self.is_latest(id) {
+                    self.record(Change::Edited(old));
+                }
+            }
+            None if self.marks.len() < MAX_MARKS => {
+                self.marks.push(mark);
+                self.record(Change::Added(id));
+            }
+            None => return false,
+        }
+        true
+    }
+
+    fn find(&self, id : u32) -> Option<usize> {
+        self.marks.iter().position(|mark| mark.id() == id)
+    }
+
+    fn is_latest(&self, id : u32) -> bool {
+        if self.done {
+            return false;
+        }
+        match self.undo.last() {
+            Some(Change::Added(last)) => *last == id,
+            Some(Change::Edited(mark)) => mark.id() == id,
+            _ => false,
+        }
+    }
+
+    fn record(&mut self, change : Change) {
+        self.undo.push(change);
+        self.redo.clear();
+        // What undo holds onto counts against the same limits as what's on screen, or clearing over and over
+        // would keep far more around. The oldest steps go first, the latest always stays.
+        while self.undo.len() > 1 && (self.undo.len() > MAX_HISTORY || self.history_marks() > MAX_MARKS || self.history_points() > MAX_POINTS) {
+            self.undo.remove(0);
+        }
+        self.done = false;
+    }
+
+    // Undoes a change, handing back the change that would undo that in turn.
+    fn revert(&mut self, change : Change) -> Option<Change> {
+        match change {
+            Change::Added(id) => {
+                let index = self.find(id)?;
+                Some(Change::Removed(index, self.marks.remove(index)))
+            }
+            Change::Edited(mark) => {
+                let index = self.find(mark.id())?;
+                Some(Change::Edited(std::mem::replace(&mut self.marks[index], mark)))
+            }
+            Change::Removed(index, mark) => {
+                let id = mark.id();
+                self.marks.insert(index.min(self.marks.len()), mark);
+                Some(Change::Added(id))
+            }
+            Change::Cleared(marks) => {
+                let newer = std::mem::replace(&mut self.marks, marks);
+                self.marks.extend(newer);
+                Some(Change::Restored)
+            }
+            Change::Restored => Some(Change::Cleared(std::mem::take(&mut self.marks))),
+        }
+    }
+
+    fn points(&self) -> usize {
+        points(&self.marks)
+    }
+
+    fn history_marks(&self) -> usize {
+        self.undo.iter().map(|change| change.held().len()).sum()
+    }
+
+    fn history_points(&self) -> usize {
+        self.undo.iter().map(|change| points(change.held())).sum()
+    }
+}
+
+impl Change {
+    // The marks it keeps around to bring back.
+    fn held(&self) -> &[Mark] {
+        match self {
+            Change::Edited(mark) | Change::Removed(_, mark) => std::slice::from_ref(mark),
+            Change::Cleared(marks) => marks,
+            Change::Added(_) | Change::Restored => &[],
+        }
+    }
+}
+
+fn points(marks : &[Mark]) -> usize {
+    marks.iter().map(|mark| match mark {
+        Mark::Stroke(stroke) => stroke.points.len(),
+        _ => 0,
+    }).sum()
+}
+
+impl Sketch {
+    pub fn new(style : StrokeStyle, text_size : u8) -> Sketch {
+        // Starts somewhere random, so a tutor that restarts doesn't reuse ids still on the student's screen.
+        Sketch { tool : Tool::Pen, style, text_size, next_id : rand::random(), layer : Ink::new(), drag : None, typing : None }
+    }
+
+    pub fn set_tool(&mut self, tool : Tool) {
+        if self.tool != tool {
+            println!("Drawing {}.", tool.describe());
+        }
+        self.tool = tool;
+    }
+
+    // Starts drawing with the current tool, which puts down any callout still being typed.
+    pub fn draw(&mut self, position : MousePosition) -> Option<PointerInput> {
+        let finished = self.finish_text();
+        if self.tool == Tool::Text {
+            self.next_id = self.next_id.wrapping_add(1);
+            self.typing = Some(Callout { id : self.next_id, position, text : String::new(), size : self.text_size, color : self.style.color });
+        } else {
+            self.drag = Some(Drag::Starting(position));
+        }
+        finished
+    }
+
+    pub fn is_typing(&self) -> bool {
+        self.typing.is_some()
+    }
+
+    // Every key typed goes out straight away, so the student sees the label being written.
+    pub fn type_text(&mut self, text : &str) -> Option<PointerInput> {
+        let callout = self.typing.as_mut()?;
+        let text : String = text.chars().filter(|c| !c.is_control()).collect();
+        if text.is_empty() {
+            return None;
+        }
+        callout.text.push_str(&text);
+        let callout = callout.clone();
+        Some(self.send(PointerInput::Callout(callout)))
+    }
+
+    pub fn backspace(&mut self) -> Option<PointerInput> {
+        let callout = self.typing.as_mut()?;
+        callout.text.pop()?;
+        let callout = callout.clone();
+        Some(self.send(PointerInput::Callout(callout)))
+    }
+
+    pub fn finish_text(&mut self) -> Option<PointerInput> {
+        match self.typing.take()? {
+            callout if callout.text.is_empty() => self.remove(callout.id),
+            _ => None,
+        }
+    }
+
+    pub fn cancel_text(&mut self) -> Option<PointerInput> {
+        let callout = self.typing.take()?;
+        self.remove(callout.id)
+    }
+
+    // Picks up the topmost shape under the pointer, if there is one.
+    pub fn grab(&mut self, position : MousePosition) {
+        if let Some(shape) = self.shape_at(position) {
+            self.drag = Some(Drag::Move { original : shape, grabbed : position, moved : shape });
+        }
+    }
+
+    // Called every sample while the button is held, returns whatever needs sending.
+    pub fn update(&mut self, position : MousePosition) -> Option<PointerInput> {
+        let (drag, input) = match self.drag.take()? {
+            Drag::Starting(start) => {
+                match self.tool {
+                    Tool::Pen => {
+                        self.next_id = self.next_id.wrapping_add(1);
+                        (Drag::Stroke(self.next_id, start), Annotation::Begin { stroke : self.next_id, style : self.style, point : start }.into())
+                    }
+                    // Nothing to show until it's been dragged out a bit.
+                    Tool::Shape(_) if start == position => {
+                        self.drag = Some(Drag::Starting(start));
+                        return None;
+                    }
+                    Tool::Shape(kind) => {
+                        self.next_id = self.next_id.wrapping_add(1);
+                        let shape = Shape { id : self.next_id, kind, style : self.style, from : start, to : position };
+                        (Drag::Shape(shape), ShapeUpdate::Place(shape).into())
+                    }
+                    // Text never drags, see draw.
+                    Tool::Text => return None,
+                }
+            }
+            Drag::Stroke(id, last) if last != position => (Drag::Stroke(id, position), Annotation::Extend { stroke : id, points : vec![position] }.into()),
+            Drag::Shape(shape) if shape.to != position => {
+                let shape = Shape { to : position, .. shape };
+                (Drag::Shape(shape), ShapeUpdate::Place(shape).into())
+            }
+            Drag::Move { original, grabbed, moved } => {
+                let (dx, dy) = (position.x - grabbed.x, position.y - grabbed.y);
+                let shape = Shape {
+                    from : MousePosition { x : original.from.x + dx, y : original.from.y + dy },
+                    to : MousePosition { x : original.to.x + dx, y : original.to.y + dy },
+                    .. original
+                };
+                if shape == moved {
+                    self.drag = Some(Drag::Move { original, grabbed, moved });
+                    return None;
+                }
+                (Drag::Move { original, grabbed, moved : shape }, ShapeUpdate::Place(shape).into())
+            }
+            drag => {
+                self.drag = Some(drag);
+                return None;
+            }
+        };
+        self.drag = Some(drag);
+        Some(self.send(input))
+    }
+
+    // The button came up, returns anything left to tidy up on the student's side.
+    pub fn release(&mut self) -> Option<PointerInput> {
+        match self.drag.take()? {
+            // Dragged back to where it started, which would leave an invisible shape behind.
+            Drag::Shape(shape) if shape.from == shape.to => self.remove(shape.id),
+            // Moving it again later is a separate step to undo.
+            Drag::Shape(_) => Some(self.send(Annotation::Done.into())),
+            Drag::Move { original, moved, .. } if original != moved => Some(self.send(Annotation::Done.into())),
+            Drag::Starting(_) | Drag::Stroke(..) | Drag::Move { .. } => None,
+        }
+    }
+
+    pub fn remove_at(&mut self, position : MousePosition) -> Option<PointerInput> {
+        let shape = self.shape_at(position)?;
+        self.remove(shape.id)
+    }
+
+    pub fn clear(&mut self) -> PointerInput {
+        self.drag = None;
+        self.typing = None;
+        self.send(Annotation::Clear.into())
+    }
+
+    // Only the student's host tutor is listened to.
+    pub fn clear_all(&mut self) -> PointerInput {
+        self.drag = None;
+        self.typing = None;
+        self.send(Annotation::ClearAll.into())
+    }
+
+    // Anything half drawn is let go first, so it doesn't come straight back.
+    pub fn undo(&mut self) -> PointerInput {
+        self.drag = None;
+        self.send(Annotation::Undo.into())
+    }
+
+    pub fn redo(&mut self) -> PointerInput {
+        self.drag = None;
+        self.send(Annotation::Redo.into())
+    }
+
+    fn remove(&mut self, id : u32) -> Option<PointerInput> {
+        Some(self.send(ShapeUpdate::Remove(id).into()))
+    }
+
+    // Everything sent goes through our copy of the layer too, so it matches what the student ends up with.
+    fn send(&mut self, input : PointerInput) -> PointerInput {
+        match &input {
+            PointerInput::Annotation(annotation) => self.layer.apply(annotation.clone()),
+            PointerInput::Shape(update) => self.layer.shape(update.clone()),
+            PointerInput::Callout(callout) => self.layer.callout(callout.clone()),
+            _ => {}
+        }
+        input
+    }
+
+    fn shape_at(&self, position : MousePosition) -> Option<Shape> {
+        self.layer.marks.iter().rev().find_map(|mark| match mark {
+            Mark::Shape(shape) => Some(*shape),
+            _ => None,
+        }.filter(|shape| {
+            let (left, right) = (shape.from.x.min(shape.to.x), shape.from.x.max(shape.to.x));
+            let (top, bottom) = (shape.from.y.min(shape.to.y), shape.from.y.max(shape.to.y));
+            position.x >= left - GRAB_MARGIN && position.x <= right + GRAB_MARGIN
+                && position.y >= top - GRAB_MARGIN && position.y <= bottom + GRAB_MARGIN
+        }))
+    }
+}
+
+impl Tool {
+    // Picks a tool from the key pressed in the tutor window.
+    pub fn from_key(key : &str) -> Option<Tool> {
+        match key.to_ascii_lowercase().as_str() {
+            "p" => Some(Tool::Pen),
+            "a" => Some(Tool::Shape(ShapeKind::Arrow)),
+            "r" => Some(Tool::Shape(ShapeKind::Rectangle)),
+            "e" => Some(Tool::Shape(ShapeKind::Ellipse)),
+            "h" => Some(Tool::Shape(ShapeKind::Highlight)),
+            "t" => Some(Tool::Text),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Tool::Pen => "freehand",
+            Tool::Shape(ShapeKind::Arrow) => "arrows",
+            Tool::Shape(ShapeKind::Rectangle) => "rectangles",
+            Tool::Shape(ShapeKind::Ellipse) => "ellipses",
+            Tool::Shape(ShapeKind::Highlight) => "highlight boxes",
+            Tool::Text => "text, click where it goes then type and press Enter",
+        }
+    }
+}
+
+impl From<Annotation> for PointerInput {
+    fn from(annotation : Annotation) -> PointerInput {
+        PointerInput::Annotation(annotation)
+    }
+}
+
+impl From<ShapeUpdate> for PointerInput {
+    fn from(update : ShapeUpdate) -> PointerInput {
+        PointerInput::Shape(update)
+    }
+}
+
+// Takes "#ff3030" or "ff3030".
+pub fn parse_color(text : &str) -> Result<[u8; 3], String> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 {
+        return Err(format!("{} isn't a colour like #ff3030", text));
+    }
+    match hex::decode(hex) {
+        Ok(bytes) => Ok([bytes[0], bytes[1], bytes[2]]),
+        Err(_) => Err(format!("{} isn't a colour like #ff3030", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum Step {
+        // Puts shape `id` at `x`, on top if it's new or moving it where it is if not.
+        Place(u32, f32),
+        Remove(u32),
+        Release,
+        Clear,
+        ClearAll,
+        Undo,
+        Redo,
+    }
+    use Step::*;
+
+    // What happens, and which shapes are left on screen where.
+    type Case = (&'static str, &'static [Step], &'static [(u32, f32)]);
+
+    fn run(steps : &[Step]) -> Ink {
+        let mut ink = Ink::new();
+        for step in steps {
+            match *step {
+                Place(id, x) => ink.shape(ShapeUpdate::Place(Shape {
+                    id,
+                    kind : ShapeKind::Rectangle,
+                    style : StrokeStyle { color : [255, 48, 48], width : 2 },
+                    from : MousePosition { x, y : 0.5 },
+                    to : MousePosition { x : x + 0.1, y : 0.6 },
+                })),
+                Remove(id) => ink.shape(ShapeUpdate::Remove(id)),
+                Release => ink.apply(Annotation::Done),
+                Clear => ink.apply(Annotation::Clear),
+                ClearAll => ink.apply(Annotation::ClearAll),
+                Undo => ink.apply(Annotation::Undo),
+                Redo => ink.apply(Annotation::Redo),
+            }
+        }
+        ink
+    }
+
+    fn on_screen(ink : &Ink) -> Vec<(u32, f32)> {
+        ink.marks.iter().map(|mark| match mark {
+            Mark::Shape(shape) => (shape.id, shape.from.x),
+            _ => unreachable!(),
+        }).collect()
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let cases : &[Case] = &[
+            ("nothing to undo", &[Undo, Redo], &[]),
+            ("added", &[Place(1, 0.1), Release, Undo], &[]),
+            ("added then redone", &[Place(1, 0.1), Release, Undo, Redo], &[(1, 0.1)]),
+            ("a drag merges into its placement", &[Place(1, 0.1), Place(1, 0.2), Release, Undo], &[]),
+            ("edited", &[Place(1, 0.1), Release, Place(1, 0.5), Release, Undo], &[(1, 0.1)]),
+            ("edited then redone", &[Place(1, 0.1), Release, Place(1, 0.5), Release, Undo, Redo], &[(1, 0.5)]),
+            ("a drag undoes as one", &[Place(1, 0.1), Release, Place(1, 0.2), Place(1, 0.3), Release, Undo], &[(1, 0.1)]),
+            ("edits either side of a release stay apart", &[Place(1, 0.1), Release, Place(1, 0.2), Release, Place(1, 0.3), Release, Undo], &[(1, 0.2)]),
+            ("removed goes back where it was", &[Place(1, 0.1), Place(2, 0.2), Place(3, 0.3), Remove(2), Undo], &[(1, 0.1), (2, 0.2), (3, 0.3)]),
+            ("removed then redone", &[Place(1, 0.1), Place(2, 0.2), Place(3, 0.3), Remove(2), Undo, Redo], &[(1, 0.1), (3, 0.3)]),
+            ("cleared", &[Place(1, 0.1), Place(2, 0.2), Clear, Undo], &[(1, 0.1), (2, 0.2)]),
+            ("clearing nothing isn't a step", &[Place(1, 0.1), Clear, Clear, Undo], &[(1, 0.1)]),
+            ("restored then cleared again", &[Place(1, 0.1), Place(2, 0.2), Clear, Undo, Redo], &[]),
+            ("restored twice", &[Place(1, 0.1), Place(2, 0.2), Clear, Undo, Redo, Undo], &[(1, 0.1), (2, 0.2)]),
+            ("drawn after a clear", &[Place(1, 0.1), Clear, Place(2, 0.2), Release, Undo, Undo], &[(1, 0.1)]),
+            ("a new mark cuts off redo", &[Place(1, 0.1), Place(2, 0.2), Undo, Place(3, 0.3), Redo], &[(1, 0.1), (3, 0.3)]),
+            ("clear all can't be undone", &[Place(1, 0.1), Place(2, 0.2), ClearAll, Undo], &[]),
+            ("clear all forgets redo", &[Place(1, 0.1), Place(2, 0.2), Undo, ClearAll, Redo], &[]),
+            ("drawing after clear all undoes on its own", &[Place(1, 0.1), ClearAll, Place(2, 0.2), Release, Undo, Undo], &[]),
+        ];
+        for (name, steps, expected) in cases {
+            assert_eq!(on_screen(&run(steps)), *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn clear_all_forgets_the_layer() {
+        let ink = run(&[Place(1, 0.1), Undo, ClearAll]);
+        assert!(!ink.has_history());
+        assert!(run(&[Place(1, 0.1), Undo]).has_history());
+    }
+
+    #[test]
+    fn cleared_strokes_count_toward_the_limit() {
+        let mut ink = Ink::new();
+        let style = StrokeStyle { color : [255, 48, 48], width : 2 };
+        let point = MousePosition { x : 0.5, y : 0.5 };
+        for stroke in 0..5 {
+            ink.apply(Annotation::Begin { stroke, style, point });
+            ink.apply(Annotation::Extend { stroke, points : vec![point; MAX_POINTS] });
+            ink.apply(Annotation::Clear);
+        }
+        assert!(ink.history_points() <= MAX_POINTS);
+        // The latest clear can still be undone.
+        ink.apply(Annotation::Undo);
+        assert_eq!(ink.points(), MAX_POINTS);
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+use std::time::Instant;
+use sha2::{Digest, Sha256};
+use crate::shared::{AssetChunk, AssetHash, AssetKind, AssetManifest};
+use crate::transport::PeerId;
+
+// Cursor sheets and animation sets are sent as a manifest followed by chunks. The student answers with an AssetStatus
+// saying how much it already has, so a cached asset isn't sent again and an interrupted upload picks up where it left off.
+pub const CHUNK_SIZE : usize = 64 * 1024;
+pub const DEFAULT_MAX_ASSET_SIZE : usize = 8 * 1024 * 1024;
+
+pub fn hash_asset(bytes : &[u8]) -> AssetHash {
+    Sha256::digest(bytes).into()
+}
+
+pub fn short_hash(hash : &AssetHash) -> String {
+    hex::encode(&hash[..6])
+}
+
+pub fn manifest_for(kind : AssetKind, bytes : &[u8], frame_count : u32) -> AssetManifest {
+    AssetManifest {
+        hash : hash_asset(bytes),
+        kind,
+        total_size : bytes.len() as u32,
+        frame_count,
+    }
+}
+
+pub fn chunks_from(hash : AssetHash, bytes : &[u8], offset : usize) -> impl Iterator<Item = AssetChunk> + '_ {
+    let offset = offset.min(bytes.len());
+    bytes[offset..].chunks(CHUNK_SIZE).enumerate().map(move |(index, data)| AssetChunk {
+        hash,
+        offset : (offset + index * CHUNK_SIZE) as u32,
+        data : data.to_vec(),
+    })
+}
+
+pub struct AssetAssembly {
+    pub manifest : AssetManifest,
+    pub data : Vec<u8>,
+    // Everyone who offered this asset gets it once it's complete.
+    pub peers : HashSet<PeerId>,
+    // When the last chunk landed, uploads that stall get thrown away.
+    pub updated : Instant,
+}
+
+impl AssetAssembly {
+    pub fn new(manifest : AssetManifest) -> AssetAssembly {
+        AssetAssembly {
+            manifest,
+            data : Vec::new(),
+            peers : HashSet::new(),
+            updated : Instant::now(),
+        }
+    }
+
+    pub fn received(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.data.len() == self.manifest.total_size as usize
+    }
+
+    // Returns false for chunks that don't continue where we left off, those get ignored.
+    pub fn push(&mut self, chunk : &AssetChunk) -> Result<bool, String> {
+        if chunk.offset as usize != self.data.len() {
+            return Ok(false);
+        }
+        if self.data.len() + chunk.data.len() > self.manifest.total_size as usize {
+            return Err(format!("it's bigger than the {} bytes it said it would be", self.manifest.total_size));
+        }
+        self.data.extend_from_slice(&chunk.data);
+        self.updated = Instant::now();
+        Ok(true)
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        if hash_asset(&self.data) != self.manifest.hash {
+            return Err("its SHA-256 doesn't match the manifest".to_string());
+        }
+        Ok(())
+    }
+}
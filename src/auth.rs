@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::relay_protocol::{format_code, parse_code, CODE_LIMIT};
+use crate::transport::PeerId;
+
+// Wrong guesses allowed before a source has to wait.
+const MAX_FAILURES : u32 = 5;
+// How long they wait, every wrong guess in the meantime starts it over.
+const LOCKOUT : Duration = Duration::from_secs(60);
+// Relay peers can come back under a new id to get more guesses, so past this many between them all every relay guess waits.
+const MAX_RELAY_FAILURES : u32 = 50;
+
+// Decides which tutors get past the hello. A one-time token is swapped for a fresh one every time it's used.
+pub enum Authenticator {
+    Open,
+    Pin(String),
+    OneTimeToken(u32),
+}
+
+impl Authenticator {
+    pub fn new(pin : &Option<String>, one_time_token : bool) -> Authenticator {
+        if one_time_token {
+            Authenticator::OneTimeToken(new_token())
+        } else {
+            match pin {
+                Some(pin) => Authenticator::Pin(pin.clone()),
+                None => Authenticator::Open,
+            }
+        }
+    }
+
+    pub fn announce(&self) {
+        match self {
+            Authenticator::Open => println!("Anyone with that can point on your screen."),
+            Authenticator::Pin(_) => println!("Tutors will also need the PIN you chose."),
+            Authenticator::OneTimeToken(token) => println!("The next tutor will also need this one-time token: {}", format_code(*token)),
+        }
+    }
+
+    pub fn check(&mut self, secret : &str) -> bool {
+        match self {
+            Authenticator::Open => true,
+            Authenticator::Pin(pin) => constant_time_eq(pin.as_bytes(), secret.as_bytes()),
+            Authenticator::OneTimeToken(token) => {
+                if parse_code(secret) != Some(*token) {
+                    return false;
+                }
+                *token = new_token();
+                println!("The one-time token was used, the next tutor will need: {}", format_code(*token));
+                true
+            }
+        }
+    }
+}
+
+// Counts wrong PINs and tokens so they can't just be guessed one after another.
+#[derive(Default)]
+pub struct Throttle {
+    failures : HashMap<String, (u32, Instant)>,
+    relay_failures : Option<(u32, Instant)>,
+}
+
+impl Throttle {
+    pub fn is_locked(&self, peer : PeerId) -> bool {
+        if peer.stable_key().is_none() && locked(self.relay_failures.as_ref(), MAX_RELAY_FAILURES) {
+            return true;
+        }
+        locked(self.failures.get(&source(peer)), MAX_FAILURES)
+    }
+
+    pub fn failed(&mut self, peer : PeerId) {
+        if peer.stable_key().is_none() {
+            count(self.relay_failures.get_or_insert((0, Instant::now())));
+        }
+        // Relay ids don't come back, so old ones are cleared out rather than piling up.
+        self.failures.retain(|_, (_, last)| last.elapsed() < LOCKOUT);
+        count(self.failures.entry(source(peer)).or_insert((0, Instant::now())));
+    }
+
+    pub fn succeeded(&mut self, peer : PeerId) {
+        self.failures.remove(&source(peer));
+    }
+}
+
+// Relay and loopback peers are counted by the id they were given, the shared relay count catches them reconnecting.
+fn source(peer : PeerId) -> String {
+    peer.stable_key().unwrap_or_else(|| peer.to_string())
+}
+
+fn locked(failures : Option<&(u32, Instant)>, limit : u32) -> bool {
+    match failures {
+        Some((count, last)) => *count >= limit && last.elapsed() < LOCKOUT,
+        None => false,
+    }
+}
+
+fn count(failures : &mut (u32, Instant)) {
+    // Once they've sat out the lockout they get a fresh set of guesses.
+    if failures.1.elapsed() >= LOCKOUT {
+        failures.0 = 0;
+    }
+    failures.0 += 1;
+    failures.1 = Instant::now();
+}
+
+fn new_token() -> u32 {
+    rand::random::<u32>() % CODE_LIMIT
+}
+
+fn constant_time_eq(a : &[u8], b : &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrong_guesses_lock_out_the_source() {
+        let mut throttle = Throttle::default();
+        let guesser = PeerId::Loopback(1);
+        for _ in 0..MAX_FAILURES - 1 {
+            throttle.failed(guesser);
+        }
+        assert!(!throttle.is_locked(guesser));
+        throttle.failed(guesser);
+        assert!(throttle.is_locked(guesser));
+        // Someone else on the relay isn't held up by them.
+        assert!(!throttle.is_locked(PeerId::Loopback(2)));
+        throttle.succeeded(guesser);
+        assert!(!throttle.is_locked(guesser));
+    }
+
+    #[test]
+    fn reconnecting_through_the_relay_hits_the_shared_cap() {
+        let mut throttle = Throttle::default();
+        for id in 0..MAX_RELAY_FAILURES as u64 {
+            throttle.failed(PeerId::Loopback(id));
+        }
+        assert!(throttle.is_locked(PeerId::Loopback(u64::MAX)));
+    }
+}
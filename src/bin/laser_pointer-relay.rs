@@ -0,0 +1,174 @@
+use clap::Parser;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[allow(dead_code)]
+#[path = "../framing.rs"]
+mod framing;
+#[allow(dead_code)]
+#[path = "../relay_protocol.rs"]
+mod relay_protocol;
+
+use framing::{read_frame, write_frame};
+use relay_protocol::{format_code, RelayMessage, CODE_LIMIT};
+
+// Messages waiting to go out to one peer. A peer that lets this fill up isn't reading, so it gets cut off.
+const QUEUE_LIMIT : usize = 256;
+
+// Lets students and tutors find each other by a short code and forwards their packets,
+// for when Steam isn't around and the student can't open a port.
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Matchmaking and relay server for laser_pointer.", long_about = None)]
+struct RelayConfig {
+    #[arg(short, long, default_value="0.0.0.0:7778")]
+    listen : String,
+}
+
+struct Peer {
+    tx : SyncSender<RelayMessage>,
+    // Shut down to cut the peer off from another thread.
+    stream : TcpStream,
+    registered : bool,
+    links : HashSet<u32>,
+}
+
+type Peers = Arc<Mutex<HashMap<u32, Peer>>>;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = RelayConfig::parse();
+    let listener = TcpListener::bind(&config.listen)?;
+    println!("Relay listening on {}", listener.local_addr()?);
+    let peers : Peers = Arc::new(Mutex::new(HashMap::new()));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let peers = peers.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_peer(stream, peers) {
+                println!("Connection error: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_peer(mut stream : TcpStream, peers : Peers) -> Result<(), Box<dyn Error>> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let handle = stream.try_clone()?;
+    let (tx, rx) = sync_channel::<RelayMessage>(QUEUE_LIMIT);
+    thread::spawn(move || {
+        for message in rx {
+            if write_frame(&mut writer, &message.encode()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let id = {
+        let mut peers = peers.lock().unwrap();
+        let mut id = rand::random::<u32>() % CODE_LIMIT;
+        while peers.contains_key(&id) {
+            id = rand::random::<u32>() % CODE_LIMIT;
+        }
+        peers.insert(id, Peer { tx : tx.clone(), stream : handle, registered : false, links : HashSet::new() });
+        id
+    };
+    // However the connection ends, the peer has to come out of the map so its code and links are freed.
+    if let Err(err) = forward(&mut stream, &peers, id, &tx) {
+        println!("Lost {}: {}", format_code(id), err);
+    }
+
+    let mut peers = peers.lock().unwrap();
+    if let Some(peer) = peers.remove(&id) {
+        for link in peer.links {
+            if let Some(other) = peers.get_mut(&link) {
+                other.links.remove(&id);
+                deliver(other, link, RelayMessage::PeerLeft(id));
+            }
+        }
+    }
+    println!("{} disconnected.", format_code(id));
+    Ok(())
+}
+
+fn forward(stream : &mut TcpStream, peers : &Peers, id : u32, tx : &SyncSender<RelayMessage>) -> Result<(), Box<dyn Error>> {
+    reply(tx, RelayMessage::Welcome(id))?;
+    while let Ok(frame) = read_frame(stream) {
+        let message = match RelayMessage::decode(&frame) {
+            Some(message) => message,
+            None => {
+                println!("{} sent something we don't understand, dropping them.", format_code(id));
+                break;
+            }
+        };
+        let mut peers = peers.lock().unwrap();
+        match message {
+            RelayMessage::Register => {
+                peers.get_mut(&id).unwrap().registered = true;
+                println!("{} registered as a student.", format_code(id));
+                reply(tx, RelayMessage::Registered(id))?;
+            }
+            RelayMessage::Join(target) => {
+                match peers.get_mut(&target) {
+                    Some(student) if student.registered && target != id => {
+                        student.links.insert(id);
+                        deliver(student, target, RelayMessage::PeerJoined(id));
+                        peers.get_mut(&id).unwrap().links.insert(target);
+                        println!("{} joined {}.", format_code(id), format_code(target));
+                        reply(tx, RelayMessage::Joined(target))?;
+                    }
+                    _ => {
+                        reply(tx, RelayMessage::Error(format!("No student with the code {}.", format_code(target))))?;
+                    }
+                }
+            }
+            RelayMessage::Leave(target) => {
+                if !peers.get_mut(&id).unwrap().links.remove(&target) {
+                    continue;
+                }
+                if let Some(peer) = peers.get_mut(&target) {
+                    peer.links.remove(&id);
+                    deliver(peer, target, RelayMessage::PeerLeft(id));
+                }
+                println!("{} dropped {}.", format_code(id), format_code(target));
+            }
+            RelayMessage::Data(target, data) => {
+                if !peers.get(&id).unwrap().links.contains(&target) {
+                    continue;
+                }
+                if let Some(peer) = peers.get(&target) {
+                    deliver(peer, target, RelayMessage::Data(id, data));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Answers go back to the peer that asked, if it isn't reading them we stop serving it.
+fn reply(tx : &SyncSender<RelayMessage>, message : RelayMessage) -> Result<(), Box<dyn Error>> {
+    match tx.try_send(message) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err("it isn't reading what we send".into()),
+        Err(TrySendError::Disconnected(_)) => Err("its connection broke".into()),
+    }
+}
+
+// Passes a message on to another peer, cutting that peer off if it has fallen too far behind.
+fn deliver(peer : &Peer, id : u32, message : RelayMessage) {
+    if let Err(TrySendError::Full(_)) = peer.tx.try_send(message) {
+        println!("Dropping {}, it isn't keeping up.", format_code(id));
+        let _ = peer.stream.shutdown(Shutdown::Both);
+    }
+}
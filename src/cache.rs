@@ -0,0 +1,42 @@
+use std::fs;
+use std::path::PathBuf;
+use crate::assets::{hash_asset, short_hash};
+use crate::shared::AssetHash;
+
+// Assets the student has already received, stored as files named after their SHA-256.
+pub struct AssetCache {
+    dir : PathBuf,
+}
+
+impl AssetCache {
+    pub fn new(dir : &str) -> AssetCache {
+        AssetCache { dir : PathBuf::from(dir) }
+    }
+
+    pub fn get(&self, hash : &AssetHash) -> Option<Vec<u8>> {
+        let path = self.path(hash);
+        let bytes = fs::read(&path).ok()?;
+        if hash_asset(&bytes) != *hash {
+            println!("Cached asset {} is corrupt, removing it.", short_hash(hash));
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        Some(bytes)
+    }
+
+    pub fn put(&self, hash : &AssetHash, bytes : &[u8]) {
+        // Written under a temporary name first so a crash never leaves a half written asset behind.
+        let path = self.path(hash);
+        let temp_path = path.with_extension("part");
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&temp_path, bytes))
+            .and_then(|_| fs::rename(&temp_path, &path));
+        if let Err(err) = result {
+            println!("Failed to cache asset {}: {}", short_hash(hash), err);
+        }
+    }
+
+    fn path(&self, hash : &AssetHash) -> PathBuf {
+        self.dir.join(hex::encode(hash))
+    }
+}
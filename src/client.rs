@@ -1,19 +1,26 @@
 use std::error::Error;
 use winit::window::{Icon, Window, WindowBuilder};
-use winit::event_loop::{ControlFlow, EventLoop};
+use winit::event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget};
 use std::rc::Rc;
 use winit::dpi::LogicalSize;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
+use std::process::exit;
 use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::num::NonZeroU32;
 use winit::event::{Event, MouseButton, WindowEvent};
+use winit::keyboard::{Key, ModifiersState, NamedKey};
 use image::GenericImageView;
-use winit::platform::windows::WindowBuilderExtWindows;
 use softbuffer::Surface;
-use steamworks::{Client, SendType, SteamId};
-use crate::{Config};
-use crate::shared::{UserState, CURSOR_SIZE, MousePosition, UserAnimationStates, APP_ID, UserPacket};
+use crate::{Config, control, osc, platform, protocol, script};
+use crate::annotation::{parse_color, Sketch, Tool};
+use crate::control::ConnectionStatus;
+use crate::assets::{chunks_from, manifest_for};
+use crate::protocol::PROTOCOL_VERSION;
+use crate::shared::{UserState, CURSOR_SIZE, MousePosition, UserAnimationStates, UserPacket, Features, Hello, HelloReply, APP_VERSION, AssetKind, AssetManifest, AssetProgress, AssetStatus, StateSample, StateUpdate, Annotation, Callout, ShapeUpdate, StrokeStyle};
+use crate::transport::{PeerId, SendMode, Transport};
 
 struct MouseState  {
     left_mouse_down : bool,
@@ -31,7 +38,39 @@ impl MouseState {
     }
 }
 
-pub fn client(config: Config) -> Result<(), Box<dyn Error>> {
+// What the network thread gets told to send, either from the window or a script.
+pub enum PointerInput {
+    // Stamped when it was sampled, the network thread can be busy with an upload by the time it gets to it.
+    State(UserState, Instant),
+    Cursor(String),
+    Annotation(Annotation),
+    Shape(ShapeUpdate),
+    Callout(Callout),
+    Status(Sender<ConnectionStatus>),
+    // Say goodbye and stop. The control and OSC listeners hold senders forever, so waiting for the channel to close isn't enough.
+    Shutdown,
+}
+
+const HEARTBEAT_INTERVAL : Duration = Duration::from_secs(1);
+const KEYFRAME_INTERVAL : Duration = Duration::from_millis(250);
+
+// Drawing that's waiting to go out.
+struct InkQueue {
+    packets : Vec<UserPacket>,
+    started : Instant,
+    warned : bool,
+}
+
+struct Upload {
+    manifest : AssetManifest,
+    bytes : Vec<u8>,
+    started : bool,
+}
+
+pub fn client(config: Config, transport: Box<dyn Transport>, server: PeerId) -> Result<(), Box<dyn Error>> {
+    if let Some(script) = config.script.clone() {
+        return headless(config, transport, server, &script);
+    }
     let icon_small_image = include_bytes!("icon.png");
     let icon_small_image = image::load_from_memory(icon_small_image).expect("Failed to load icon image from memory?? uh oh");
     let (icon_width, icon_height) = icon_small_image.dimensions();
@@ -43,57 +82,34 @@ pub fn client(config: Config) -> Result<(), Box<dyn Error>> {
     let icon_big = Icon::from_rgba(icon_big_image.into_bytes(), icon_width, icon_height)?;
 
     let event_loop = EventLoop::new().unwrap();
-    let window = Rc::new(WindowBuilder::new().with_title("Laser Pointer")
+    let window = Rc::new(platform::tutor_window(WindowBuilder::new().with_title("Laser Pointer")
         .with_min_inner_size(LogicalSize::new(200, 80))
         .with_transparent(true)
-        .with_taskbar_icon(Some(icon_big))
-        .with_window_icon(Some(icon_small))
+        .with_window_icon(Some(icon_small)), icon_big)
         .build(&event_loop).unwrap());
     event_loop.set_control_flow(ControlFlow::Wait);
 
+    let sample_interval = Duration::from_secs_f32(1.0 / config.sample_rate.max(1) as f32);
+    let mut next_sample = Instant::now();
     let mut laser_state = UserState::Idle;
     let mut mouse_state = MouseState::new();
-    let (tx, rx): (Sender<UserState>, Receiver<UserState>) = channel();
-
-    let (steam_client, single_client) = Client::init_app(APP_ID)?;
-    thread::spawn(move || {
-        loop {
-            single_client.run_callbacks();
-            let interval = std::time::Duration::from_millis(16);
-            thread::sleep(interval);
-        }
-    });
+    let mut sketch = Sketch::new(StrokeStyle { color : parse_color(&config.ink_color)?, width : config.ink_width }, config.text_size);
+    let mut modifiers = ModifiersState::empty();
+    let (tx, rx): (Sender<PointerInput>, Receiver<PointerInput>) = channel();
+    if let Some(addr) = &config.control {
+        control::serve(addr, tx.clone())?;
+    }
+    if let Some(addr) = &config.osc {
+        osc::listen(addr, tx.clone())?;
+    }
 
-    let steam_server_id = SteamId::from_raw(config.steam_id);
-    thread::spawn(move || {
-        let networking = steam_client.networking();
-        if &config.cursor_path != "" {
-            let file_bytes = std::fs::read(&config.cursor_path).expect("Failed to read cursor image."); // The file is compressed.
-            let image = image::load_from_memory(&*file_bytes).expect("Failed to read cursor image.");
-            if image.height() != CURSOR_SIZE || image.width()%CURSOR_SIZE != 0 {
-                println!("Failed to load user image, its height needs to be {}, and the width needs to be a multiple of {}!", CURSOR_SIZE, CURSOR_SIZE);
-            } else {
-                println!("Sent server a cursor of size {}", &file_bytes.len());
-                networking.send_p2p_packet(steam_server_id, SendType::Reliable, &*file_bytes);
-            }
-        }
-        if &config.animation_json_path != "" {
-            let animations = get_animations(&config.animation_json_path).unwrap();
-            let packet = UserPacket::AnimationSet(animations);
-            let packet_string = serde_json::to_string(&packet).unwrap();
-            println!("Sent server custom animation states.");
-            networking.send_p2p_packet(steam_server_id, SendType::Reliable, packet_string.as_ref());
-        }
-        loop {
-            let item = rx.recv().expect("Failed to read from main thread.");
-            let packet = UserPacket::State(item);
-            let packet_string = serde_json::to_string(&packet).unwrap();
-            networking.send_p2p_packet(steam_server_id, SendType::UnreliableNoDelay, packet_string.as_ref());
-        }
+    let network = thread::spawn(move || {
+        client_network(config, transport, server, rx);
     });
 
     let context = softbuffer::Context::new(window.clone()).expect("Failed to create graphics context.");
     let mut surface = Surface::new(&context, window.clone()).expect("Failed to create graphics surface.");
+    let shutdown = tx.clone();
     let (mut width, mut height) = {
         let size = window.inner_size();
         (size.width,size.height)
@@ -111,6 +127,14 @@ pub fn client(config: Config) -> Result<(), Box<dyn Error>> {
                 elwt.exit();
             },
             Event::AboutToWait => {
+                // Positions are sampled at a steady rate rather than on every event, so the student sees the same timing we do.
+                let now = Instant::now();
+                if now < next_sample {
+                    elwt.set_control_flow(ControlFlow::WaitUntil(next_sample));
+                    return;
+                }
+                next_sample = (next_sample + sample_interval).max(now);
+                elwt.set_control_flow(ControlFlow::WaitUntil(next_sample));
                 let old_laser_state = laser_state.clone();
                 if mouse_state.left_mouse_down && mouse_state.right_mouse_down {
                     laser_state = UserState::Flashing(mouse_state.position.clone());
@@ -120,9 +144,17 @@ pub fn client(config: Config) -> Result<(), Box<dyn Error>> {
                     laser_state = UserState::Idle;
                 }
 
+                // Drawing still shows the pointer, the ink just follows it along.
+                if let Some(input) = sketch.update(mouse_state.position) {
+                    forward(&tx, input, elwt);
+                }
+
                 if old_laser_state != laser_state {
                     let copy = laser_state.to_owned();
-                    tx.send(copy).unwrap();
+                    forward(&tx, PointerInput::State(copy, now), elwt);
+                } else {
+                    // Nothing's moving, sleep until the next event instead of waking up at the sample rate.
+                    elwt.set_control_flow(ControlFlow::Wait);
                 }
                 window.request_redraw();
             },
@@ -150,6 +182,26 @@ pub fn client(config: Config) -> Result<(), Box<dyn Error>> {
                             left_mouse_down : state.is_pressed(),
                             .. mouse_state
                         };
+                        // Ctrl grabs a shape to move it, shift draws with the current tool.
+                        if state.is_pressed() && modifiers.control_key() {
+                            sketch.grab(mouse_state.position);
+                        } else if state.is_pressed() && modifiers.shift_key() {
+                            if let Some(input) = sketch.draw(mouse_state.position) {
+                                forward(&tx, input, elwt);
+                            }
+                        } else if !state.is_pressed() {
+                            // Whatever moved since the last sample still needs to go out.
+                            for input in sketch.update(mouse_state.position).into_iter().chain(sketch.release()) {
+                                forward(&tx, input, elwt);
+                            }
+                        }
+                    }
+                    MouseButton::Right if modifiers.control_key() => {
+                        if state.is_pressed() {
+                            if let Some(input) = sketch.remove_at(mouse_state.position) {
+                                forward(&tx, input, elwt);
+                            }
+                        }
                     }
                     MouseButton::Right => {
                         mouse_state = MouseState {
@@ -163,6 +215,53 @@ pub fn client(config: Config) -> Result<(), Box<dyn Error>> {
                     MouseButton::Other(_) => {}
                 }
             },
+            Event::WindowEvent {
+                event: WindowEvent::ModifiersChanged(new_modifiers),
+                ..
+            } => {
+                modifiers = new_modifiers.state();
+            },
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput {
+                    event: key,
+                    ..
+                },
+                ..
+            } if key.state.is_pressed() => {
+                // While a callout is being written every key belongs to it.
+                let input = if sketch.is_typing() {
+                    match &key.logical_key {
+                        Key::Named(NamedKey::Enter) => sketch.finish_text(),
+                        Key::Named(NamedKey::Escape) => sketch.cancel_text(),
+                        Key::Named(NamedKey::Backspace) => sketch.backspace(),
+                        _ => key.text.as_ref().and_then(|text| sketch.type_text(text)),
+                    }
+                } else {
+                    match &key.logical_key {
+                        Key::Named(NamedKey::Delete | NamedKey::Backspace) if !key.repeat && modifiers.shift_key() => {
+                            println!("Asked the student to clear everyone's ink, they'll only do it if you're their host.");
+                            Some(sketch.clear_all())
+                        }
+                        Key::Named(NamedKey::Delete | NamedKey::Backspace) if !key.repeat => Some(sketch.clear()),
+                        Key::Character(character) if modifiers.control_key() => match character.to_lowercase().as_str() {
+                            "z" if modifiers.shift_key() => Some(sketch.redo()),
+                            "z" => Some(sketch.undo()),
+                            "y" => Some(sketch.redo()),
+                            _ => None,
+                        },
+                        Key::Character(character) if !key.repeat => {
+                            if let Some(tool) = Tool::from_key(character) {
+                                sketch.set_tool(tool);
+                            }
+                            None
+                        }
+                        _ => None,
+                    }
+                };
+                if let Some(input) = input {
+                    forward(&tx, input, elwt);
+                }
+            },
             Event::WindowEvent {
                 event: WindowEvent::CursorMoved {
                     position,
@@ -182,9 +281,335 @@ pub fn client(config: Config) -> Result<(), Box<dyn Error>> {
             _ => ()
         }
     }).unwrap();
+    let _ = shutdown.send(PointerInput::Shutdown);
+    let _ = network.join();
     Ok(())
 }
 
+pub fn client_network(config: Config, mut transport: Box<dyn Transport>, server: PeerId, rx: Receiver<PointerInput>) {
+    let reply = join_student(&config, transport.as_mut(), server);
+    send_pointer(config, transport, server, reply, rx);
+}
+
+// The network thread only stops early when something went wrong, and it will have said what.
+fn forward(tx : &Sender<PointerInput>, input : PointerInput, elwt : &EventLoopWindowTarget<()>) {
+    if tx.send(input).is_err() {
+        elwt.exit();
+    }
+}
+
+// Drives the pointer from a script instead of the window, see script.rs for the commands.
+fn headless(config: Config, mut transport: Box<dyn Transport>, server: PeerId, script: &str) -> Result<(), Box<dyn Error>> {
+    let script : Box<dyn BufRead> = if script == "-" {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(script)?))
+    };
+    // Waiting for the student first keeps the script's timing from being eaten by the handshake.
+    let reply = join_student(&config, transport.as_mut(), server);
+    let sample_interval = Duration::from_secs_f32(1.0 / config.sample_rate.max(1) as f32);
+    let (tx, rx) = channel();
+    if let Some(addr) = &config.control {
+        control::serve(addr, tx.clone())?;
+    }
+    if let Some(addr) = &config.osc {
+        osc::listen(addr, tx.clone())?;
+    }
+    let network = thread::spawn(move || {
+        send_pointer(config, transport, server, reply, rx);
+    });
+    script::run(script, &tx, sample_interval);
+    let _ = tx.send(PointerInput::Shutdown);
+    let _ = network.join();
+    Ok(())
+}
+
+fn join_student(config: &Config, transport: &mut dyn Transport, server: PeerId) -> HelloReply {
+    if let Err(err) = transport.connect(server) {
+        println!("Failed to connect to {}: {}", server, err);
+        exit(1);
+    }
+    let reply = match handshake(config, transport, server) {
+        Ok(reply) => reply,
+        Err(err) => {
+            println!("Failed to connect to {}: {}", server, err);
+            exit(1);
+        }
+    };
+    if !reply.accepted {
+        println!("The student rejected us: {}", reply.reason);
+        exit(1);
+    }
+    println!("Connected to {}, they're running laser_pointer {}", server, reply.app_version);
+    reply
+}
+
+fn send_pointer(config: Config, mut transport: Box<dyn Transport>, server: PeerId, reply: HelloReply, rx: Receiver<PointerInput>) {
+    let mut uploads = Vec::new();
+    if &config.cursor_path != "" {
+        offer_cursor(transport.as_mut(), server, &reply, &config.cursor_path, &mut uploads);
+    }
+    if &config.animation_json_path != "" {
+        if !reply.features.contains(Features::CUSTOM_ANIMATIONS) {
+            println!("The student doesn't support custom animations, not sending ours.");
+        } else {
+            match get_animations(&config.animation_json_path).and_then(|animations| Ok(protocol::encode_animation_set(&animations)?)) {
+                Ok(bytes) => {
+                    println!("Offering the server custom animation states.");
+                    uploads.push(Upload::offer(transport.as_mut(), server, AssetKind::AnimationSet, bytes, 0));
+                }
+                Err(err) => println!("Failed to load animations from {}: {}", config.animation_json_path, err),
+            }
+        }
+    }
+    let samples_per_packet = config.samples_per_packet.max(1) as usize;
+    let sample_interval = Duration::from_secs_f32(1.0 / config.sample_rate.max(1) as f32);
+    // Don't sit on a half full batch for longer than it would take to fill it.
+    let batch_window = sample_interval * samples_per_packet as u32;
+    let mut batch : Vec<StateSample> = Vec::new();
+    let mut batch_started = Instant::now();
+    let mut last_heartbeat = Instant::now();
+    let mut last_keyframe = Instant::now();
+    let mut sequence = 0;
+    // Keyframes are stamped when they go out, so a sample that sat in the queue behind one mustn't go back in time.
+    let mut last_timestamp = 0;
+    let mut current_state = UserState::Idle;
+    let connected_at = Instant::now();
+    let mut ink = InkQueue { packets : Vec::new(), started : Instant::now(), warned : false };
+    loop {
+        match rx.recv_timeout(sample_interval.min(Duration::from_millis(16))) {
+            Ok(PointerInput::Cursor(path)) => offer_cursor(transport.as_mut(), server, &reply, &path, &mut uploads),
+            Ok(PointerInput::Annotation(annotation)) => ink.push(&reply, UserPacket::Annotation(annotation)),
+            Ok(PointerInput::Shape(update)) => ink.push(&reply, UserPacket::Shape(update)),
+            Ok(PointerInput::Callout(callout)) => ink.push(&reply, UserPacket::Callout(callout)),
+            Ok(PointerInput::Status(status)) => {
+                let _ = status.send(ConnectionStatus {
+                    student : server.to_string(),
+                    student_version : reply.app_version.clone(),
+                    state : current_state.clone(),
+                });
+            }
+            Ok(PointerInput::State(item, sampled)) => {
+                // Presses and releases go out straight away, only movement waits for the batch to fill.
+                let pressed = std::mem::discriminant(&item) != std::mem::discriminant(&current_state);
+                if batch.is_empty() {
+                    batch_started = Instant::now();
+                }
+                last_timestamp = (sampled.saturating_duration_since(connected_at).as_millis() as u32).max(last_timestamp);
+                batch.push(StateSample {
+                    timestamp : last_timestamp,
+                    state : item.clone(),
+                });
+                current_state = item;
+                if pressed {
+                    send_states(transport.as_mut(), server, &mut sequence, &mut batch);
+                    last_keyframe = Instant::now();
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(PointerInput::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                send_states(transport.as_mut(), server, &mut sequence, &mut batch);
+                ink.send(transport.as_mut(), server);
+                transport.send(server, SendMode::Reliable, &protocol::encode(&UserPacket::Goodbye));
+                break;
+            }
+        }
+        if !batch.is_empty() && (batch.len() >= samples_per_packet || batch_started.elapsed() >= batch_window) {
+            send_states(transport.as_mut(), server, &mut sequence, &mut batch);
+            last_keyframe = Instant::now();
+        }
+        if !ink.packets.is_empty() && ink.started.elapsed() >= batch_window {
+            ink.send(transport.as_mut(), server);
+        }
+        // States go out unreliably, resending the current one now and then means a lost packet can't leave the cursor stuck.
+        if last_keyframe.elapsed() > KEYFRAME_INTERVAL {
+            last_timestamp = (connected_at.elapsed().as_millis() as u32).max(last_timestamp);
+            batch.push(StateSample {
+                timestamp : last_timestamp,
+                state : current_state.clone(),
+            });
+            send_states(transport.as_mut(), server, &mut sequence, &mut batch);
+            last_keyframe = Instant::now();
+        }
+        // Reliable, so tutors whose UDP never gets through aren't timed out while they sit still.
+        if last_heartbeat.elapsed() > HEARTBEAT_INTERVAL {
+            transport.send(server, SendMode::Reliable, &protocol::encode(&UserPacket::Heartbeat));
+            last_heartbeat = Instant::now();
+        }
+        while let Some((peer, buf)) = transport.receive() {
+            if peer != server {
+                continue;
+            }
+            match protocol::decode(&buf) {
+                Ok(UserPacket::AssetStatus(status)) => {
+                    if let Some(upload) = uploads.iter_mut().find(|upload| upload.manifest.hash == status.hash) {
+                        upload.handle_status(transport.as_mut(), server, status);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => println!("Ignoring packet from the student: {}", err),
+            }
+        }
+        if transport.closed() == Some(server) {
+            println!("Lost the connection to the student.");
+            exit(1);
+        }
+    }
+}
+
+fn offer_cursor(transport: &mut dyn Transport, server: PeerId, reply: &HelloReply, path: &str, uploads: &mut Vec<Upload>) {
+    if !reply.features.contains(Features::CUSTOM_CURSORS) {
+        println!("The student doesn't support custom cursors, not sending ours.");
+        return;
+    }
+    // Scripts can switch cursors mid-session, so a bad file shouldn't take the connection down.
+    let file_bytes = match std::fs::read(path) { // The file is compressed.
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("Failed to read cursor image {}: {}", path, err);
+            return;
+        }
+    };
+    let image = match image::load_from_memory(&*file_bytes) {
+        Ok(image) => image,
+        Err(err) => {
+            println!("Failed to read cursor image {}: {}", path, err);
+            return;
+        }
+    };
+    if image.height() != CURSOR_SIZE || image.width()%CURSOR_SIZE != 0 {
+        println!("Failed to load user image, its height needs to be {}, and the width needs to be a multiple of {}!", CURSOR_SIZE, CURSOR_SIZE);
+        return;
+    }
+    println!("Offering the server a cursor of size {}", &file_bytes.len());
+    uploads.push(Upload::offer(transport, server, AssetKind::CursorSheet, file_bytes, image.width()/CURSOR_SIZE));
+}
+
+fn send_states(transport: &mut dyn Transport, server: PeerId, sequence: &mut u32, batch: &mut Vec<StateSample>) {
+    if batch.is_empty() {
+        return;
+    }
+    *sequence = sequence.wrapping_add(1);
+    let packet = UserPacket::State(StateUpdate {
+        sequence : *sequence,
+        samples : std::mem::take(batch),
+    });
+    transport.send(server, SendMode::Unreliable, &protocol::encode(&packet));
+}
+
+impl InkQueue {
+    // Points drawn one sample at a time are merged, and shapes being dragged out or callouts being typed only send how they ended up, so drawing goes out in batches like the pointer does.
+    fn push(&mut self, reply: &HelloReply, packet: UserPacket) {
+        if !reply.features.contains(Features::ANNOTATIONS) {
+            if !self.warned {
+                println!("The student doesn't support drawing, not sending our ink.");
+                self.warned = true;
+            }
+            return;
+        }
+        if let UserPacket::Annotation(annotation @ (Annotation::Undo | Annotation::Redo | Annotation::Done | Annotation::ClearAll)) = &packet {
+            if !reply.features.contains(Features::UNDO) {
+                if *annotation != Annotation::Done {
+                    println!("The student's laser_pointer is too old to undo or clear everyone's ink.");
+                }
+                return;
+            }
+        }
+        if self.packets.is_empty() {
+            self.started = Instant::now();
+        }
+        match (self.packets.last_mut(), &packet) {
+            (Some(UserPacket::Annotation(Annotation::Extend { stroke, points })), UserPacket::Annotation(Annotation::Extend { stroke : next, points : more })) if stroke == next => {
+                points.extend_from_slice(more);
+            }
+            (Some(UserPacket::Shape(ShapeUpdate::Place(shape))), UserPacket::Shape(ShapeUpdate::Place(next))) if shape.id == next.id => {
+                *shape = *next;
+            }
+            (Some(UserPacket::Callout(callout)), UserPacket::Callout(next)) if callout.id == next.id => {
+                *callout = next.clone();
+            }
+            _ => self.packets.push(packet),
+        }
+    }
+
+    // Ink has to arrive in one piece and in order, unlike the pointer.
+    fn send(&mut self, transport: &mut dyn Transport, server: PeerId) {
+        for packet in self.packets.drain(..) {
+            transport.send(server, SendMode::Reliable, &protocol::encode(&packet));
+        }
+    }
+}
+
+impl Upload {
+    fn offer(transport: &mut dyn Transport, server: PeerId, kind: AssetKind, bytes: Vec<u8>, frame_count: u32) -> Upload {
+        let manifest = manifest_for(kind, &bytes, frame_count);
+        transport.send(server, SendMode::Reliable, &protocol::encode(&UserPacket::AssetManifest(manifest.clone())));
+        Upload { manifest, bytes, started : false }
+    }
+
+    fn handle_status(&mut self, transport: &mut dyn Transport, server: PeerId, status: AssetStatus) {
+        let name = self.manifest.kind.describe();
+        match status.progress {
+            AssetProgress::Receiving if !self.started => {
+                if status.received > 0 {
+                    println!("Resuming {} upload from {} of {} bytes.", name, status.received, self.manifest.total_size);
+                }
+                for chunk in chunks_from(self.manifest.hash, &self.bytes, status.received as usize) {
+                    transport.send(server, SendMode::Reliable, &protocol::encode(&UserPacket::AssetChunk(chunk)));
+                }
+                self.started = true;
+            }
+            AssetProgress::Receiving => {
+                println!("Uploading {}... {}%", name, status.received as u64 * 100 / self.manifest.total_size.max(1) as u64);
+            }
+            AssetProgress::Complete if !self.started => println!("The student already had our {} cached.", name),
+            AssetProgress::Complete => println!("The student received our {}.", name),
+            AssetProgress::Failed(reason) => println!("The student couldn't use our {}: {}", name, reason),
+        }
+    }
+}
+
+// Made up the first time we connect anywhere and reused after that.
+fn install_id(path : &str) -> String {
+    if let Ok(id) = std::fs::read_to_string(path) {
+        if !id.trim().is_empty() {
+            return id.trim().to_string();
+        }
+    }
+    let id = hex::encode(rand::random::<[u8; 16]>());
+    let saved = std::path::Path::new(path).parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|_| std::fs::write(path, &id));
+    if let Err(err) = saved {
+        println!("Couldn't save our install id to {}, students won't recognise us if we reconnect: {}", path, err);
+    }
+    id
+}
+
+fn handshake(config: &Config, transport: &mut dyn Transport, server: PeerId) -> Result<HelloReply, Box<dyn Error>> {
+    let hello = Hello::new(config.display_name(), config.pin.clone().unwrap_or_default(), install_id(&config.install_id));
+    transport.send(server, SendMode::Reliable, &protocol::encode(&UserPacket::Hello(hello)));
+    println!("Waiting for the student to let us in...");
+    // Long enough for the student to notice the prompt and answer it.
+    let deadline = Instant::now() + Duration::from_secs(120);
+    while Instant::now() < deadline {
+        match transport.receive() {
+            None => thread::sleep(Duration::from_millis(10)),
+            Some((peer, _)) if peer != server => {}
+            Some((_, buf)) => match protocol::decode(&buf) {
+                Ok(UserPacket::HelloReply(reply)) => {
+                    if reply.protocol_version != PROTOCOL_VERSION {
+                        println!("The student is running laser_pointer {} with protocol {}, we're running {} with protocol {}.",
+                                 reply.app_version, reply.protocol_version, APP_VERSION, PROTOCOL_VERSION);
+                    }
+                    return Ok(reply);
+                }
+                Ok(_) => {}
+                Err(err) => println!("Ignoring packet from the student: {}", err),
+            },
+        }
+    }
+    Err("The student never answered our hello.".into())
+}
+
 fn get_animations(json_path : &str) -> Result<UserAnimationStates, Box<dyn Error>> {
     if json_path == "" {
         return Ok(UserAnimationStates::new());
@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use serde::{Deserialize, Serialize};
+use crate::transport::PeerId;
+
+// Tutors the student already made their mind up about, stored as json so it can be edited by hand.
+#[derive(Serialize,Deserialize,Default)]
+struct TrustList {
+    allow : Vec<TrustEntry>,
+    deny : Vec<TrustEntry>,
+}
+
+#[derive(Serialize,Deserialize)]
+struct TrustEntry {
+    id : String,
+    name : String,
+}
+
+#[derive(PartialEq,Clone,Copy)]
+enum Decision {
+    Allow,
+    Deny,
+    AlwaysAllow,
+    AlwaysDeny,
+}
+
+struct ConsentRequest {
+    peer : PeerId,
+    description : String,
+}
+
+// The terminal is shared, lines answer whatever's being asked and anything else is a command for the network thread.
+enum Prompt {
+    Request(ConsentRequest),
+    Line(String),
+    Closed,
+}
+
+pub struct Consent {
+    path : String,
+    list : TrustList,
+    names : Vec<(PeerId, String)>,
+    prompt : bool,
+    requests : Sender<Prompt>,
+    decisions : Receiver<(PeerId, Decision)>,
+    commands : Receiver<String>,
+}
+
+impl Consent {
+    pub fn new(path : &str, prompt : bool) -> Consent {
+        let list = match load_list(path) {
+            Ok(list) => list,
+            Err(err) => {
+                if Path::new(path).exists() {
+                    println!("Failed to read the tutor list at {}, starting with an empty one: {}", path, err);
+                }
+                TrustList::default()
+            }
+        };
+        let (decisions_tx, decisions) = channel();
+        let (commands_tx, commands) = channel();
+        let (requests, requests_rx) = channel();
+        let lines = requests.clone();
+        thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => if lines.send(Prompt::Line(line)).is_err() {
+                        return;
+                    },
+                    Err(_) => break,
+                }
+            }
+            let _ = lines.send(Prompt::Closed);
+        });
+        thread::spawn(move || {
+            prompt_thread(requests_rx, decisions_tx, commands_tx);
+        });
+        Consent {
+            path : path.to_string(),
+            list,
+            names : Vec::new(),
+            prompt,
+            requests,
+            decisions,
+            commands,
+        }
+    }
+
+    // Some(true) to let them in straight away, Some(false) to turn them away, None when the student has to be asked.
+    pub fn lookup(&self, peer : PeerId) -> Option<bool> {
+        if let Some(id) = peer.stable_key() {
+            if self.list.deny.iter().any(|entry| entry.id == id) {
+                return Some(false);
+            }
+            if self.list.allow.iter().any(|entry| entry.id == id) {
+                return Some(true);
+            }
+        }
+        match self.prompt {
+            false => Some(true),
+            true => None,
+        }
+    }
+
+    pub fn ask(&mut self, peer : PeerId, name : &str, description : String) {
+        // Already waiting on an answer for them, a repeated hello doesn't get asked twice.
+        if self.names.iter().any(|(other, _)| *other == peer) {
+            return;
+        }
+        self.names.push((peer, name.to_string()));
+        let _ = self.requests.send(Prompt::Request(ConsentRequest { peer, description }));
+    }
+
+    // Lines typed in the terminal while nobody was waiting to be let in.
+    pub fn command(&mut self) -> Option<String> {
+        self.commands.try_recv().ok()
+    }
+
+    pub fn poll(&mut self) -> Option<(PeerId, bool)> {
+        let (peer, decision) = self.decisions.try_recv().ok()?;
+        let name = match self.names.iter().position(|(other, _)| *other == peer) {
+            Some(index) => self.names.remove(index).1,
+            None => String::new(),
+        };
+        let allowed = decision == Decision::Allow || decision == Decision::AlwaysAllow;
+        if decision == Decision::AlwaysAllow || decision == Decision::AlwaysDeny {
+            match peer.stable_key() {
+                None => println!("{} can't be recognized next time over this kind of connection, you'll be asked again.", name),
+                Some(id) => {
+                    self.list.allow.retain(|entry| entry.id != id);
+                    self.list.deny.retain(|entry| entry.id != id);
+                    let entry = TrustEntry { id, name };
+                    if allowed {
+                        self.list.allow.push(entry);
+                    } else {
+                        self.list.deny.push(entry);
+                    }
+                    if let Err(err) = save_list(&self.path, &self.list) {
+                        println!("Failed to save the tutor list to {}: {}", self.path, err);
+                    }
+                }
+            }
+        }
+        Some((peer, allowed))
+    }
+}
+
+fn prompt_thread(inputs : Receiver<Prompt>, decisions : Sender<(PeerId, Decision)>, commands : Sender<String>) {
+    let mut waiting : VecDeque<ConsentRequest> = VecDeque::new();
+    let mut closed = false;
+    for input in inputs {
+        match input {
+            Prompt::Request(request) => {
+                waiting.push_back(request);
+                // Anyone else waiting gets asked once the first one's answered.
+                if waiting.len() == 1 && !closed {
+                    ask(&waiting[0]);
+                }
+            }
+            Prompt::Line(line) => {
+                let request = match waiting.front() {
+                    Some(request) => request,
+                    None => {
+                        let _ = commands.send(line);
+                        continue;
+                    }
+                };
+                let decision = match line.trim().to_lowercase().as_str() {
+                    "y" | "yes" => Decision::Allow,
+                    "n" | "no" => Decision::Deny,
+                    "a" | "always" => Decision::AlwaysAllow,
+                    "v" | "never" => Decision::AlwaysDeny,
+                    _ => {
+                        ask(request);
+                        continue;
+                    }
+                };
+                if decisions.send((request.peer, decision)).is_err() {
+                    return;
+                }
+                waiting.pop_front();
+                if let Some(next) = waiting.front() {
+                    ask(next);
+                }
+            }
+            Prompt::Closed => closed = true,
+        }
+        if closed && !waiting.is_empty() {
+            println!("Nobody to ask, turning them away.");
+            for request in waiting.drain(..) {
+                if decisions.send((request.peer, Decision::Deny)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn ask(request : &ConsentRequest) {
+    println!("{} wants to point on your screen. Allow? [y]es, [n]o, [a]lways, ne[v]er", request.description);
+}
+
+fn load_list(path : &str) -> Result<TrustList, Box<dyn Error>> {
+    Ok(serde_json::from_reader(File::open(path)?)?)
+}
+
+fn save_list(path : &str, list : &TrustList) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    serde_json::to_writer_pretty(File::create(path)?, list)?;
+    Ok(())
+}
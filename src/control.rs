@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::client::PointerInput;
+use crate::shared::UserState;
+use crate::transport::resolve;
+
+// Nobody should be sending us more than a cursor path or a pointer state.
+const MAX_BODY_SIZE : usize = 64 * 1024;
+
+// A tiny HTTP API for other tools on this machine to drive the pointer, everything is JSON.
+//   GET  /status   what we're connected to and what the pointer is doing
+//   POST /pointer  a pointer state, {"state":"Visible","x":0.5,"y":0.5}, "Flashing" or {"state":"Idle"}
+//   POST /cursor   {"path":"C:/cursors/gator.png"}, switches to another cursor sheet
+#[derive(Serialize)]
+pub struct ConnectionStatus {
+    pub student : String,
+    pub student_version : String,
+    pub state : UserState,
+}
+
+#[derive(Deserialize)]
+struct CursorRequest {
+    path : String,
+}
+
+struct Request {
+    method : String,
+    path : String,
+    host : String,
+    json : bool,
+    body : Vec<u8>,
+}
+
+pub fn serve(addr : &str, tx : Sender<PointerInput>) -> Result<(), Box<dyn Error>> {
+    let addr = resolve(addr)?;
+    if !addr.ip().is_loopback() {
+        return Err(format!("the control API only listens on this machine, {} isn't a loopback address", addr.ip()).into());
+    }
+    let listener = TcpListener::bind(addr)?;
+    println!("Listening for control requests on http://{}", listener.local_addr()?);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let (status, body) = match read_request(&mut stream) {
+                Ok(request) => handle(request, &tx),
+                Err(err) => (400, error(&err)),
+            };
+            let _ = write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           status, reason(status), body.len(), body);
+        }
+    });
+    Ok(())
+}
+
+fn handle(request : Request, tx : &Sender<PointerInput>) -> (u16, String) {
+    // Web pages can reach localhost too, checking the host stops DNS rebinding and requiring JSON means browsers have to ask first, which we never allow.
+    let host = match request.host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => request.host.as_str(),
+    };
+    if !matches!(host, "localhost" | "127.0.0.1" | "[::1]") {
+        return (403, error("requests have to be addressed to localhost"));
+    }
+    if request.method == "POST" && !request.json {
+        return (415, error("send a Content-Type of application/json"));
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => {
+            let (status_tx, status_rx) = channel();
+            if tx.send(PointerInput::Status(status_tx)).is_err() {
+                return (503, error("the connection to the student has closed"));
+            }
+            match status_rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(status) => (200, serde_json::to_string(&status).unwrap()),
+                Err(RecvTimeoutError::Timeout) => (503, error("still waiting for the student to let us in")),
+                Err(RecvTimeoutError::Disconnected) => (503, error("the connection to the student has closed")),
+            }
+        }
+        ("POST", "/pointer") => match serde_json::from_slice::<UserState>(&request.body) {
+            Ok(state) => forward(tx, PointerInput::State(state, Instant::now())),
+            Err(err) => (400, error(&err.to_string())),
+        },
+        ("POST", "/cursor") => match serde_json::from_slice::<CursorRequest>(&request.body) {
+            Ok(cursor) => forward(tx, PointerInput::Cursor(cursor.path)),
+            Err(err) => (400, error(&err.to_string())),
+        },
+        (_, "/status" | "/pointer" | "/cursor") => (405, error("wrong method")),
+        _ => (404, error("no such endpoint")),
+    }
+}
+
+fn forward(tx : &Sender<PointerInput>, input : PointerInput) -> (u16, String) {
+    match tx.send(input) {
+        Ok(()) => (200, "{}".to_string()),
+        Err(_) => (503, error("the connection to the student has closed")),
+    }
+}
+
+fn read_request(stream : &mut TcpStream) -> Result<Request, String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|err| err.to_string())?;
+    let mut words = line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return Err("couldn't read the request line".to_string()),
+    };
+    let mut request = Request {
+        method : method.to_string(),
+        path : path.to_string(),
+        host : String::new(),
+        json : false,
+        body : Vec::new(),
+    };
+    let mut length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            return Err("the request ended early".to_string());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = match header.split_once(':') {
+            Some(header) => header,
+            None => return Err("couldn't read a header".to_string()),
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "host" => request.host = value.to_string(),
+            "content-type" => request.json = value.starts_with("application/json"),
+            "content-length" => length = value.parse().map_err(|_| "bad content length".to_string())?,
+            _ => {}
+        }
+    }
+    if length > MAX_BODY_SIZE {
+        return Err("the body is too big".to_string());
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body).map_err(|err| err.to_string())?;
+    Ok(request)
+}
+
+fn error(message : &str) -> String {
+    serde_json::json!({ "error" : message }).to_string()
+}
+
+fn reason(status : u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Service Unavailable",
+    }
+}
@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use crate::annotation::Mark;
+use crate::overlay::Tutor;
+use crate::render::{arrow_head, callout_layout, compose_ink, RgbaTarget, Screen, HIGHLIGHT_OPACITY, PLATE_COLOR, PLATE_OPACITY};
+use crate::shared::{MousePosition, ShapeKind, APP_VERSION};
+
+// Saves what the tutors drew on the primary monitor as `<base>.png` and `<base>.svg`, tutors are drawn in the order given.
+pub fn export(base : &str, screen : &Screen, tutors : &[&Tutor]) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = Path::new(base).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_png(&format!("{}.png", base), screen, tutors)?;
+    std::fs::write(format!("{}.svg", base), svg(screen, tutors))?;
+    Ok(())
+}
+
+fn write_png(path : &str, screen : &Screen, tutors : &[&Tutor]) -> Result<(), Box<dyn Error>> {
+    let mut target = RgbaTarget::new(screen.size.width, screen.size.height);
+    compose_ink(&mut target, screen, screen, tutors);
+    // The overlay blends into premultiplied pixels, PNGs want them straight.
+    for pixel in target.image.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        if a > 0 {
+            let unmix = |channel : u8| (channel as u32 * 255 / a as u32).min(255) as u8;
+            pixel.0 = [unmix(r), unmix(g), unmix(b), a];
+        }
+    }
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), screen.size.width, screen.size.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_text_chunk("Software".to_string(), format!("laser_pointer {}", APP_VERSION))?;
+    for tutor in tutors {
+        // Names can be anything, tEXt chunks only hold Latin-1.
+        encoder.add_itxt_chunk("Tutor".to_string(), describe(tutor))?;
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(target.image.as_raw())?;
+    Ok(())
+}
+
+// Each tutor gets a group of their own, so their layer can be picked out or hidden in an editor.
+fn svg(screen : &Screen, tutors : &[&Tutor]) -> String {
+    let (width, height) = (screen.size.width, screen.size.height);
+    let to_screen = |position : &MousePosition| (position.x * width as f32, position.y * height as f32);
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#, width, height, width, height);
+    let _ = writeln!(out, "<desc>Drawn with laser_pointer {}</desc>", APP_VERSION);
+    for (index, tutor) in tutors.iter().enumerate() {
+        let _ = writeln!(out, r#"<g id="tutor-{}" data-name="{}" data-id="{}" data-connected="{}">"#, index + 1, escape(&tutor.name), escape(&tutor.layer), tutor.connected);
+        let _ = writeln!(out, "<title>{}</title>", escape(&describe(tutor)));
+        for mark in &tutor.ink.marks {
+            match mark {
+                Mark::Stroke(stroke) => {
+                    let mut path = String::new();
+                    for (index, point) in stroke.points.iter().enumerate() {
+                        let (x, y) = to_screen(point);
+                        let _ = write!(path, "{}{:.1} {:.1} ", if index == 0 { "M" } else { "L" }, x, y);
+                    }
+                    // A lone point still leaves a dot, same as on screen.
+                    if stroke.points.len() == 1 {
+                        let (x, y) = to_screen(&stroke.points[0]);
+                        let _ = write!(path, "L{:.1} {:.1}", x, y);
+                    }
+                    let _ = writeln!(out, r#"<path d="{}" {}/>"#, path.trim_end(), line_style(stroke.style.color, stroke.style.width));
+                }
+                Mark::Shape(shape) => {
+                    let (from, to) = (to_screen(&shape.from), to_screen(&shape.to));
+                    let (left, top) = (from.0.min(to.0), from.1.min(to.1));
+                    let (right, bottom) = (from.0.max(to.0), from.1.max(to.1));
+                    let style = line_style(shape.style.color, shape.style.width);
+                    match shape.kind {
+                        ShapeKind::Arrow => {
+                            let _ = write!(out, r#"<path d="M{:.1} {:.1} L{:.1} {:.1}"#, from.0, from.1, to.0, to.1);
+                            for barb in arrow_head(from, to, shape.style.width.max(1) as f32) {
+                                let _ = write!(out, " M{:.1} {:.1} L{:.1} {:.1}", to.0, to.1, barb.0, barb.1);
+                            }
+                            let _ = writeln!(out, r#"" {}/>"#, style);
+                        }
+                        ShapeKind::Rectangle => {
+                            let _ = writeln!(out, r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" {}/>"#, left, top, right - left, bottom - top, style);
+                        }
+                        ShapeKind::Ellipse => {
+                            let _ = writeln!(out, r#"<ellipse cx="{:.1}" cy="{:.1}" rx="{:.1}" ry="{:.1}" {}/>"#, (left + right) / 2.0, (top + bottom) / 2.0, (right - left) / 2.0, (bottom - top) / 2.0, style);
+                        }
+                        ShapeKind::Highlight => {
+                            let _ = writeln!(out, r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}" fill-opacity="{}"/>"#, left, top, right - left, bottom - top, hex(shape.style.color), HIGHLIGHT_OPACITY);
+                        }
+                    }
+                }
+                Mark::Callout(callout) => {
+                    if callout.text.is_empty() {
+                        continue;
+                    }
+                    let layout = callout_layout(callout, to_screen(&callout.position));
+                    let [left, top, right, bottom] = layout.plate;
+                    let _ = writeln!(out, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}" fill-opacity="{}"/>"#, left, top, right - left, bottom - top, hex(PLATE_COLOR), PLATE_OPACITY);
+                    let _ = writeln!(out, r#"<text x="{:.1}" y="{:.1}" font-family="DejaVu Sans, sans-serif" font-size="{}" fill="{}" xml:space="preserve">{}</text>"#, layout.origin.0, layout.origin.1, layout.size, hex(callout.color), escape(&callout.text));
+                }
+            }
+        }
+        let _ = writeln!(out, "</g>");
+    }
+    let _ = writeln!(out, "</svg>");
+    out
+}
+
+fn describe(tutor : &Tutor) -> String {
+    let name = if tutor.name.is_empty() { "A tutor" } else { &tutor.name };
+    let id = if tutor.layer.is_empty() { String::new() } else { format!(" ({})", tutor.layer) };
+    let left = if tutor.connected { "" } else { ", had left" };
+    format!("{}{}, {} marks{}", name, id, tutor.ink.marks.len(), left)
+}
+
+fn line_style(color : [u8; 3], width : u8) -> String {
+    format!(r#"fill="none" stroke="{}" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round""#, hex(color), width.max(1))
+}
+
+fn hex(color : [u8; 3]) -> String {
+    format!("#{}", hex::encode(color))
+}
+
+// Control characters aren't allowed in XML at all, even escaped.
+fn escape(text : &str) -> String {
+    text.replace(|c : char| c.is_control(), "").replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use winit::dpi::{PhysicalPosition, PhysicalSize};
+    use crate::shared::{Callout, Shape, ShapeUpdate, StrokeStyle};
+    use crate::smoothing::Smoothing;
+    use super::*;
+
+    const RED : [u8; 3] = [255, 48, 48];
+
+    fn tutor() -> Tutor {
+        let mut tutor = Tutor::new(Smoothing { latency : Duration::ZERO, max_extrapolation : Duration::ZERO });
+        tutor.name = "Ms <Frizzle> 李".to_string();
+        tutor.layer = "steam:1".to_string();
+        let style = StrokeStyle { color : RED, width : 2 };
+        tutor.ink.shape(ShapeUpdate::Place(Shape { id : 1, kind : ShapeKind::Highlight, style, from : MousePosition { x : 0.5, y : 0.5 }, to : MousePosition { x : 1.0, y : 1.0 } }));
+        tutor.ink.callout(Callout { id : 2, position : MousePosition { x : 0.1, y : 0.1 }, text : "a & b\u{7}".to_string(), size : 24, color : [255, 255, 255] });
+        tutor
+    }
+
+    #[test]
+    fn exports_png_and_svg() {
+        let dir = std::env::temp_dir().join(format!("laser_pointer-export-{}", std::process::id()));
+        let base = dir.join("Lesson-One");
+        let screen = Screen { origin : PhysicalPosition::new(0, 0), size : PhysicalSize::new(320, 200) };
+        let tutor = tutor();
+        export(base.to_str().unwrap(), &screen, &[&tutor]).unwrap();
+
+        // Saved under the name it was given, case and all.
+        let mut names : Vec<String> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        assert_eq!(names, ["Lesson-One.png", "Lesson-One.svg"]);
+
+        let svg = std::fs::read_to_string(base.with_extension("svg")).unwrap();
+        assert!(svg.contains(r#"data-name="Ms &lt;Frizzle&gt; 李""#));
+        assert!(svg.contains(">a &amp; b</text>"));
+        assert!(!svg.contains('\u{7}'));
+
+        let decoder = png::Decoder::new(File::open(base.with_extension("png")).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert!(reader.info().utf8_text.iter().any(|text| text.keyword == "Tutor" && text.get_text().unwrap().starts_with("Ms <Frizzle> 李")));
+        let image = image::open(base.with_extension("png")).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (320, 200));
+        // Outside the highlight nothing was drawn, inside it's the tutor's colour at the highlight's opacity,
+        // give or take what premultiplying rounds away.
+        assert_eq!(image.get_pixel(300, 20).0[3], 0);
+        let [r, g, b, a] = image.get_pixel(240, 150).0;
+        assert!([r, g, b].iter().zip(RED).all(|(got, want)| got.abs_diff(want) <= 4));
+        assert!((a as f32 - HIGHLIGHT_OPACITY * 255.0).abs() <= 1.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+// Frames are a u32 little endian length followed by that many bytes.
+pub const MAX_FRAME_SIZE : usize = 16 * 1024 * 1024;
+
+pub fn take_frame(buffer : &mut Vec<u8>) -> Option<Result<Vec<u8>, ()>> {
+    if buffer.len() < 4 {
+        return None;
+    }
+    let size = u32::from_le_bytes(buffer[..4].try_into().unwrap()) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Some(Err(()));
+    }
+    if buffer.len() < 4 + size {
+        return None;
+    }
+    let frame = buffer[4..4 + size].to_vec();
+    buffer.drain(..4 + size);
+    Some(Ok(frame))
+}
+
+pub fn read_frame(stream : &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut size = [0; 4];
+    stream.read_exact(&mut size)?;
+    let size = u32::from_le_bytes(size) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    let mut frame = vec![0; size];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+pub fn frame(data : &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+// Writes as much of `pending` as a non-blocking stream takes right now, returning how much that was.
+pub fn flush_pending(stream : &mut impl Write, pending : &mut Vec<u8>) -> std::io::Result<usize> {
+    let mut written = 0;
+    while written < pending.len() {
+        match stream.write(&pending[written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(amt) => written += amt,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    pending.drain(..written);
+    Ok(written)
+}
+
+// Works on non-blocking streams too, it just waits for room in the send buffer.
+pub fn write_frame(stream : &mut impl Write, data : &[u8]) -> std::io::Result<()> {
+    let frame = frame(data);
+    let mut written = 0;
+    while written < frame.len() {
+        match stream.write(&frame[written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(amt) => written += amt,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
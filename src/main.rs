@@ -1,28 +1,191 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::error::Error;
+use std::f32::consts::TAU;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use crate::assets::DEFAULT_MAX_ASSET_SIZE;
+use crate::shared::{MousePosition, UserState};
+use crate::transport::{parse_join_code, resolve, DirectTransport, LoopbackNetwork, PeerId, RelayTransport, SteamTransport, Transport};
 
 mod server;
 mod shared;
 mod client;
+mod annotation;
+mod consent;
+mod control;
+mod export;
+mod assets;
+mod auth;
+mod cache;
+mod osc;
+mod overlay;
+mod platform;
+mod protocol;
+mod recording;
+mod render;
+mod script;
+mod smoothing;
+// Also built into laser_pointer-relay, which uses a different subset of them.
+#[allow(dead_code)]
+mod framing;
+#[allow(dead_code)]
+mod relay_protocol;
+mod transport;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let config : Config = Config::new();
-    if config.steam_id == 0 {
-        server::server(config)
+    if let Some(Command::Replay { file }) = &config.command {
+        let file = shellexpand::full(file)?.to_string();
+        server::replay(config, &file)
+    } else if config.loopback {
+        loopback(config)
+    } else if let Some(addr) = &config.relay {
+        let mut transport = RelayTransport::connect_relay(addr)?;
+        match &config.join {
+            None => {
+                transport.register()?;
+                server::server(config, Box::new(transport))
+            }
+            Some(code) => {
+                let server = parse_join_code(code)?;
+                client::client(config, Box::new(transport), server)
+            }
+        }
+    } else if let Some(addr) = &config.listen {
+        let transport = DirectTransport::listen(addr)?;
+        server::server(config, Box::new(transport))
+    } else if let Some(addr) = &config.connect {
+        let server = PeerId::Ip(resolve(addr)?);
+        client::client(config, Box::new(DirectTransport::new()), server)
+    } else if config.steam_id == 0 {
+        server::server(config, Box::new(SteamTransport::new()?))
     } else {
-        client::client(config)
+        let server = PeerId::Steam(steamworks::SteamId::from_raw(config.steam_id));
+        client::client(config, Box::new(SteamTransport::new()?), server)
     }
 }
 
-#[derive(Parser, Debug)]
+// Runs the server with a fake tutor connected over an in-memory transport, handy for checking the overlay without Steam.
+fn loopback(mut config: Config) -> Result<(), Box<dyn Error>> {
+    config.auto_accept = true;
+    let network = LoopbackNetwork::new();
+    let server_transport = network.endpoint();
+    let server_id = server_transport.local_id();
+    let client_transport = network.endpoint();
+
+    let (tx, rx) = channel();
+    let client_config = config.clone();
+    thread::spawn(move || {
+        client::client_network(client_config, Box::new(client_transport), server_id, rx);
+    });
+    thread::spawn(move || {
+        let start = SystemTime::now();
+        loop {
+            let time = start.elapsed().unwrap().as_secs_f32();
+            let position = MousePosition {
+                x : 0.5 + 0.25 * (time * TAU / 4.0).cos(),
+                y : 0.5 + 0.25 * (time * TAU / 4.0).sin(),
+            };
+            let state = match time as u32 % 6 {
+                0..=3 => UserState::Visible(position),
+                4 => UserState::Flashing(position),
+                _ => UserState::Idle,
+            };
+            if tx.send(client::PointerInput::State(state, Instant::now())).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(16));
+        }
+    });
+    server::server(config, Box::new(server_transport))
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long, default_value="0")]
     steam_id : u64,
     #[arg(short, long, default_value="")]
     cursor_path: String,
     #[arg(long, default_value="")]
     animation_json_path: String,
+    #[arg(long)]
+    loopback: bool,
+    #[arg(long, conflicts_with = "connect")]
+    listen: Option<String>,
+    #[arg(long)]
+    connect: Option<String>,
+    #[arg(long, conflicts_with_all = ["listen", "connect"])]
+    relay: Option<String>,
+    #[arg(long, requires = "relay")]
+    join: Option<String>,
+    #[arg(long)]
+    name: Option<String>,
+    #[arg(long, default_value_t = DEFAULT_MAX_ASSET_SIZE)]
+    max_cursor_size: usize,
+    #[arg(long, default_value="~/.laser_pointer/cache")]
+    cache_dir: String,
+    #[arg(long, conflicts_with = "one_time_token")]
+    require_pin: Option<String>,
+    #[arg(long)]
+    one_time_token: bool,
+    #[arg(long)]
+    pin: Option<String>,
+    #[arg(long)]
+    auto_accept: bool,
+    #[arg(long, default_value="~/.laser_pointer/tutors.json")]
+    tutor_list: String,
+    #[arg(long, default_value="5.0", value_parser = parse_seconds)]
+    timeout: f32,
+    #[arg(long, default_value="60")]
+    smoothing_latency: u64,
+    #[arg(long, default_value="0")]
+    extrapolation: u64,
+    #[arg(long, default_value="120")]
+    sample_rate: u32,
+    #[arg(long, default_value="4")]
+    samples_per_packet: u8,
+    #[arg(long)]
+    record: Option<String>,
+    #[arg(long)]
+    script: Option<String>,
+    #[arg(long)]
+    control: Option<String>,
+    #[arg(long)]
+    osc: Option<String>,
+    #[arg(long, value_enum, default_value_t = RenderMode::Windows)]
+    render_mode: RenderMode,
+    #[arg(long, default_value="#ff3030")]
+    ink_color: String,
+    #[arg(long, default_value="4")]
+    ink_width: u8,
+    #[arg(long, default_value="24")]
+    text_size: u8,
+    #[arg(long)]
+    host: Option<String>,
+    #[arg(long, default_value="~/.laser_pointer/exports")]
+    export_dir: String,
+    #[arg(long, default_value="~/.laser_pointer/install_id")]
+    install_id: String,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    /// A small window per tutor, moved around the screen
+    Windows,
+    /// One see-through window per monitor with every tutor drawn into it
+    Overlay,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Play back a session saved with --record
+    Replay {
+        file: String,
+    },
 }
 
 impl Config {
@@ -30,6 +193,28 @@ impl Config {
         let mut output = Config::parse();
         output.cursor_path = shellexpand::full(&output.cursor_path).unwrap().to_string();
         output.animation_json_path = shellexpand::full(&output.animation_json_path).unwrap().to_string();
+        output.cache_dir = shellexpand::full(&output.cache_dir).unwrap().to_string();
+        output.tutor_list = shellexpand::full(&output.tutor_list).unwrap().to_string();
+        output.export_dir = shellexpand::full(&output.export_dir).unwrap().to_string();
+        output.install_id = shellexpand::full(&output.install_id).unwrap().to_string();
+        output.record = output.record.map(|path| shellexpand::full(&path).unwrap().to_string());
+        output.script = output.script.map(|path| shellexpand::full(&path).unwrap().to_string());
         output
     }
+
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => std::env::var("USERNAME").or_else(|_| std::env::var("USER")).unwrap_or("tutor".to_string()),
+        }
+    }
+}
+
+// A number of seconds that's positive and fits in a Duration.
+fn parse_seconds(value : &str) -> Result<f32, String> {
+    let seconds : f32 = value.parse().map_err(|_| format!("{} isn't a number", value))?;
+    match seconds > 0.0 && Duration::try_from_secs_f32(seconds).is_ok() {
+        true => Ok(seconds),
+        false => Err(format!("{} isn't a positive number of seconds", value)),
+    }
 }
@@ -0,0 +1,161 @@
+use std::error::Error;
+use std::net::UdpSocket;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Instant;
+use crate::client::PointerInput;
+use crate::shared::{MousePosition, UserState};
+use crate::transport::resolve;
+
+// Bundles can nest, but nobody needs them this deep.
+const MAX_BUNDLE_DEPTH : usize = 8;
+
+// Understands these, numbers can be ints or floats and anything but zero counts as on:
+//   /pointer/xy <x> <y>  positions go from 0 to 1 across the screen
+//   /pointer/press <on>
+//   /pointer/flash <on>
+struct Message {
+    address : String,
+    arguments : Vec<f32>,
+}
+
+struct OscPointer {
+    position : MousePosition,
+    pressed : bool,
+    flashing : bool,
+}
+
+pub fn listen(addr : &str, tx : Sender<PointerInput>) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind(resolve(addr)?)?;
+    println!("Listening for OSC on {}", socket.local_addr()?);
+    thread::spawn(move || {
+        let mut pointer = OscPointer {
+            position : MousePosition { x : 0.5, y : 0.5 },
+            pressed : false,
+            flashing : false,
+        };
+        let mut state = UserState::Idle;
+        let mut buf = [0; 65536];
+        loop {
+            let amt = match socket.recv_from(&mut buf) {
+                Ok((amt, _)) => amt,
+                Err(_) => continue,
+            };
+            let mut messages = Vec::new();
+            if let Err(err) = parse_packet(&buf[..amt], 0, &mut messages) {
+                println!("Ignoring OSC packet: {}", err);
+                continue;
+            }
+            for message in messages {
+                pointer.apply(message);
+            }
+            let new_state = pointer.state();
+            if new_state != state {
+                state = new_state;
+                if tx.send(PointerInput::State(state.clone(), Instant::now())).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+impl OscPointer {
+    fn apply(&mut self, message : Message) {
+        match (message.address.as_str(), message.arguments.as_slice()) {
+            ("/pointer/xy", [x, y, ..]) => {
+                self.position = MousePosition { x : x.clamp(0.0, 1.0), y : y.clamp(0.0, 1.0) };
+            }
+            ("/pointer/press", [on, ..]) => self.pressed = *on != 0.0,
+            ("/pointer/flash", [on, ..]) => self.flashing = *on != 0.0,
+            _ => {}
+        }
+    }
+
+    // Same rules as the mouse, flashing wins over pressing.
+    fn state(&self) -> UserState {
+        if self.flashing {
+            UserState::Flashing(self.position)
+        } else if self.pressed {
+            UserState::Visible(self.position)
+        } else {
+            UserState::Idle
+        }
+    }
+}
+
+fn parse_packet(bytes : &[u8], depth : usize, messages : &mut Vec<Message>) -> Result<(), String> {
+    if !bytes.starts_with(b"#bundle\0") {
+        messages.push(parse_message(bytes)?);
+        return Ok(());
+    }
+    if depth >= MAX_BUNDLE_DEPTH {
+        return Err("bundles nested too deep".to_string());
+    }
+    // Skip the time tag, everything is acted on as soon as it arrives.
+    let mut offset = 16;
+    while offset < bytes.len() {
+        let size = i32::from_be_bytes(take(bytes, &mut offset, 4)?.try_into().unwrap());
+        if size < 0 {
+            return Err("negative bundle element size".to_string());
+        }
+        parse_packet(take(bytes, &mut offset, size as usize)?, depth + 1, messages)?;
+    }
+    Ok(())
+}
+
+fn parse_message(bytes : &[u8]) -> Result<Message, String> {
+    let mut offset = 0;
+    let address = string(bytes, &mut offset)?;
+    // Very old senders leave out the type tags, there's nothing we can do with their arguments.
+    if offset >= bytes.len() {
+        return Ok(Message { address, arguments : Vec::new() });
+    }
+    let tags = string(bytes, &mut offset)?;
+    let tags = match tags.strip_prefix(',') {
+        Some(tags) => tags,
+        None => return Err("missing type tags".to_string()),
+    };
+    let mut arguments = Vec::new();
+    for tag in tags.chars() {
+        match tag {
+            'i' => arguments.push(i32::from_be_bytes(take(bytes, &mut offset, 4)?.try_into().unwrap()) as f32),
+            'f' => arguments.push(f32::from_be_bytes(take(bytes, &mut offset, 4)?.try_into().unwrap())),
+            'h' => arguments.push(i64::from_be_bytes(take(bytes, &mut offset, 8)?.try_into().unwrap()) as f32),
+            'd' => arguments.push(f64::from_be_bytes(take(bytes, &mut offset, 8)?.try_into().unwrap()) as f32),
+            'T' => arguments.push(1.0),
+            'F' | 'N' | 'I' => arguments.push(0.0),
+            's' | 'S' => {
+                string(bytes, &mut offset)?;
+            }
+            'b' => {
+                let size = i32::from_be_bytes(take(bytes, &mut offset, 4)?.try_into().unwrap()).max(0) as usize;
+                take(bytes, &mut offset, size.next_multiple_of(4))?;
+            }
+            _ => return Err(format!("unsupported argument type {}", tag)),
+        }
+    }
+    Ok(Message { address, arguments })
+}
+
+// Strings are null terminated and padded out to a multiple of 4 bytes.
+fn string(bytes : &[u8], offset : &mut usize) -> Result<String, String> {
+    let rest = &bytes[(*offset).min(bytes.len())..];
+    let end = match rest.iter().position(|byte| *byte == 0) {
+        Some(end) => end,
+        None => return Err("unterminated string".to_string()),
+    };
+    let text = String::from_utf8_lossy(&rest[..end]).to_string();
+    take(bytes, offset, (end + 1).next_multiple_of(4))?;
+    Ok(text)
+}
+
+fn take<'a>(bytes : &'a [u8], offset : &mut usize, amount : usize) -> Result<&'a [u8], String> {
+    if bytes.len() < *offset || bytes.len() - *offset < amount {
+        return Err("truncated packet".to_string());
+    }
+    let taken = &bytes[*offset..*offset + amount];
+    *offset += amount;
+    Ok(taken)
+}
@@ -0,0 +1,300 @@
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroU32;
+use std::rc::Rc;
+use std::time::Instant;
+use image::{DynamicImage, GenericImageView};
+use softbuffer::Surface;
+use winit::dpi::{LogicalPosition, LogicalSize};
+use winit::event_loop::EventLoopWindowTarget;
+use winit::window::{Window, WindowBuilder, WindowLevel};
+use crate::annotation::Ink;
+use crate::platform;
+use crate::render::{compose, compose_ink, BufferTarget, Screen, TRAIL_DURATION};
+use crate::shared::{MousePosition, UserAnimationStates, UserState, CURSOR_SIZE};
+use crate::smoothing::{JitterBuffer, Smoothing};
+use crate::transport::PeerId;
+
+// Everything we know about a connected tutor's cursor, however it ends up on screen.
+pub struct Tutor {
+    pub state : UserState,
+    pub motion : JitterBuffer,
+    pub animation_set : UserAnimationStates,
+    pub image : DynamicImage,
+    // Bumped whenever the image changes, so renderers know to redraw it.
+    pub image_version : u32,
+    pub frame : u32,
+    pub trail : VecDeque<(Instant, MousePosition)>,
+    pub trail_color : [u8; 3],
+    pub ink : Ink,
+    // Who the ink belongs to, kept across reconnects so a tutor gets their layer back.
+    pub layer : String,
+    pub name : String,
+    // Gone tutors stick around while they still have ink up.
+    pub connected : bool,
+}
+
+// A way of putting tutors' cursors on the student's screen, called every time round the event loop.
+pub trait Overlay {
+    fn draw(&mut self, event_loop : &EventLoopWindowTarget<()>, tutors : &HashMap<PeerId, Tutor>);
+}
+
+// One small window per tutor, moved around the screen.
+pub struct TutorWindows {
+    windows : HashMap<PeerId, TutorWindow>,
+    // Ink needs the whole screen, so it gets monitor sized windows once someone starts drawing.
+    ink : Option<Compositor>,
+}
+
+struct TutorWindow {
+    window : Rc<Window>,
+    surface : Surface<Rc<Window>, Rc<Window>>,
+    state : UserState,
+    frame : u32,
+    image_version : u32,
+}
+
+// One window covering each monitor, with every tutor drawn into it.
+pub struct Compositor {
+    monitors : Vec<MonitorOverlay>,
+    // Leaves the cursors out when they have windows of their own.
+    cursors : bool,
+    // What the last frame showed, so nothing gets redrawn while everyone sits still.
+    drawn : Vec<(PeerId, UserState, u32, u32, usize, u32)>,
+}
+
+struct MonitorOverlay {
+    surface : Surface<Rc<Window>, Rc<Window>>,
+    screen : Screen,
+}
+
+impl Tutor {
+    pub fn new(smoothing : Smoothing) -> Tutor {
+        let pointer_image_bytes = include_bytes!("pointer.png");
+        let pointer_image = image::load_from_memory(pointer_image_bytes).expect("Failed to load pointer image from memory?? uh oh");
+        let mut tutor = Tutor {
+            state : UserState::Idle,
+            motion : JitterBuffer::new(smoothing),
+            animation_set : UserAnimationStates::new(),
+            image : DynamicImage::new_rgba8(CURSOR_SIZE, CURSOR_SIZE),
+            image_version : 0,
+            frame : 0,
+            trail : VecDeque::new(),
+            trail_color : [255, 255, 255],
+            ink : Ink::new(),
+            layer : String::new(),
+            name : String::new(),
+            connected : true,
+        };
+        tutor.set_image(pointer_image);
+        tutor
+    }
+
+    pub fn set_image(&mut self, image : DynamicImage) {
+        self.trail_color = average_color(&image);
+        self.image = image;
+        self.image_version = self.image_version.wrapping_add(1);
+    }
+
+    // Puts the cursor away but keeps the ink, see the Joined handling in server.rs.
+    pub fn leave(&mut self, smoothing : Smoothing) {
+        self.connected = false;
+        self.state = UserState::Idle;
+        self.motion = JitterBuffer::new(smoothing);
+        self.trail.clear();
+    }
+
+    pub fn update(&mut self, elapsed : f32, now : Instant) {
+        if let Some(state) = self.motion.sample(now) {
+            self.state = state;
+        }
+        match &self.state {
+            UserState::Visible(_) => self.frame = self.animation_set.visible.get_frame(elapsed).index,
+            UserState::Flashing(_) => self.frame = self.animation_set.flashing.get_frame(elapsed).index,
+            UserState::Idle => self.trail.clear(),
+        }
+        if let Some(position) = self.state.position() {
+            if self.trail.back().map(|(_, last)| *last != position).unwrap_or(true) {
+                self.trail.push_back((now, position));
+            }
+        }
+        while self.trail.front().map(|(time, _)| now - *time > TRAIL_DURATION).unwrap_or(false) {
+            self.trail.pop_front();
+        }
+    }
+}
+
+impl TutorWindows {
+    pub fn new() -> TutorWindows {
+        TutorWindows { windows : HashMap::new(), ink : None }
+    }
+}
+
+impl Overlay for TutorWindows {
+    fn draw(&mut self, event_loop : &EventLoopWindowTarget<()>, tutors : &HashMap<PeerId, Tutor>) {
+        self.windows.retain(|peer, _| tutors.get(peer).map(|tutor| tutor.connected).unwrap_or(false));
+        for (peer, tutor) in tutors.iter().filter(|(_, tutor)| tutor.connected) {
+            let user_info = self.windows.entry(*peer).or_insert_with(|| create_tutor_window(event_loop));
+            if user_info.state != tutor.state {
+                place_window(&user_info.window, &tutor.state);
+                user_info.state = tutor.state.clone();
+            }
+            if user_info.frame != tutor.frame || user_info.image_version != tutor.image_version {
+                set_frame(&mut user_info.surface, &tutor.image, tutor.frame);
+                user_info.frame = tutor.frame;
+                user_info.image_version = tutor.image_version;
+            }
+        }
+        if self.ink.is_none() && tutors.values().any(|tutor| !tutor.ink.marks.is_empty()) {
+            self.ink = Some(Compositor::new(event_loop, false));
+        }
+        if let Some(ink) = &mut self.ink {
+            ink.draw(event_loop, tutors);
+        }
+    }
+}
+
+impl Compositor {
+    pub fn new(event_loop : &EventLoopWindowTarget<()>, cursors : bool) -> Compositor {
+        let mut monitors = Vec::new();
+        for monitor in event_loop.available_monitors() {
+            let size = monitor.size();
+            let (width, height) = match (NonZeroU32::new(size.width), NonZeroU32::new(size.height)) {
+                (Some(width), Some(height)) => (width, height),
+                _ => continue,
+            };
+            let window = Rc::new(platform::overlay_window(WindowBuilder::new().with_title("Laser Pointer")
+                .with_decorations(false)
+                .with_position(monitor.position())
+                .with_inner_size(size)
+                .with_resizable(false)
+                .with_window_level(WindowLevel::AlwaysOnTop)
+                .with_transparent(true))
+                .build(event_loop).expect("Failed to build window"));
+            window.set_cursor_hittest(false).expect("Failed to set window to be passthrough.");
+            let context = softbuffer::Context::new(window.clone()).expect("Failed to create graphics context.");
+            let mut surface = Surface::new(&context, window.clone()).expect("Failed to create graphics surface.");
+            surface.resize(width, height).unwrap();
+            let mut buffer = surface.buffer_mut().unwrap();
+            buffer.fill(0);
+            buffer.present().unwrap();
+            monitors.push(MonitorOverlay {
+                surface,
+                screen : Screen { origin : monitor.position(), size },
+            });
+        }
+        Compositor { monitors, cursors, drawn : Vec::new() }
+    }
+}
+
+impl Overlay for Compositor {
+    fn draw(&mut self, event_loop : &EventLoopWindowTarget<()>, tutors : &HashMap<PeerId, Tutor>) {
+        let mut snapshot : Vec<_> = tutors.iter()
+            .map(|(peer, tutor)| match self.cursors {
+                true => (*peer, tutor.state.clone(), tutor.frame, tutor.image_version, tutor.trail.len(), tutor.ink.version),
+                // Only the ink is drawn here, the cursors moving about don't matter.
+                false => (*peer, UserState::Idle, 0, 0, 0, tutor.ink.version),
+            })
+            .collect();
+        snapshot.sort_by_key(|(peer, ..)| peer.to_string());
+        if snapshot == self.drawn {
+            return;
+        }
+        self.drawn = snapshot;
+        // Tutors point at the primary monitor, same as the per tutor windows.
+        let primary = match event_loop.primary_monitor().or_else(|| event_loop.available_monitors().next()) {
+            Some(monitor) => Screen { origin : monitor.position(), size : monitor.size() },
+            None => return,
+        };
+        // Drawn in a fixed order so overlapping cursors don't swap places from frame to frame.
+        let mut ordered : Vec<(&PeerId, &Tutor)> = tutors.iter().collect();
+        ordered.sort_by_key(|(peer, _)| peer.to_string());
+        let ordered : Vec<&Tutor> = ordered.into_iter().map(|(_, tutor)| tutor).collect();
+        let now = Instant::now();
+        for monitor in &mut self.monitors {
+            let mut buffer = monitor.surface.buffer_mut().unwrap();
+            let mut target = BufferTarget {
+                pixels : &mut buffer,
+                width : monitor.screen.size.width,
+                height : monitor.screen.size.height,
+            };
+            if self.cursors {
+                compose(&mut target, &monitor.screen, &primary, &ordered, now);
+            } else {
+                compose_ink(&mut target, &monitor.screen, &primary, &ordered);
+            }
+            buffer.present().unwrap();
+        }
+    }
+}
+
+// Trails take on the colour of the cursor they follow.
+fn average_color(image : &DynamicImage) -> [u8; 3] {
+    let (mut total, mut count) = ([0u64; 3], 0u64);
+    for (_, _, pixel) in image.view(0, 0, CURSOR_SIZE.min(image.width()), CURSOR_SIZE.min(image.height())).pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a > 127 {
+            total[0] += r as u64;
+            total[1] += g as u64;
+            total[2] += b as u64;
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return [255, 255, 255];
+    }
+    [(total[0] / count) as u8, (total[1] / count) as u8, (total[2] / count) as u8]
+}
+
+fn place_window(window : &Window, state : &UserState) {
+    let monitor_size = window.primary_monitor().expect("Failed to detect primary monitor.").size();
+    match state {
+        UserState::Idle => {
+            window.set_outer_position(LogicalPosition::new(-1000, -1000));
+        }
+        UserState::Visible(position) | UserState::Flashing(position) => {
+            window.set_outer_position(LogicalPosition::new(position.x * monitor_size.width as f32, position.y * monitor_size.height as f32));
+        }
+    }
+}
+
+fn set_frame(surface : &mut Surface<Rc<Window>,Rc<Window>>, image : &DynamicImage, frame : u32) {
+    if frame >= image.width() / CURSOR_SIZE {
+        return;
+    }
+    let mut buffer = surface.buffer_mut().unwrap();
+    let image_crop = image.crop_imm(frame*CURSOR_SIZE,0,CURSOR_SIZE,CURSOR_SIZE);
+    for index in 0..(CURSOR_SIZE * CURSOR_SIZE) {
+        let y = index / CURSOR_SIZE;
+        let x = index % CURSOR_SIZE;
+        let [r, g, b, a] = image_crop.get_pixel(x,y).0;
+        buffer[index as usize] = u32::from_be_bytes([a, r, g, b]);
+    }
+    buffer.present().unwrap();
+}
+
+fn create_tutor_window(event_loop : &EventLoopWindowTarget<()>) -> TutorWindow {
+    let window = Rc::new(platform::overlay_window(WindowBuilder::new().with_title("Laser Pointer")
+        .with_decorations(false)
+        .with_inner_size(LogicalSize::new(64, 64))
+        .with_resizable(false)
+        .with_window_level(WindowLevel::AlwaysOnTop)
+        .with_transparent(true))
+        .build(event_loop).expect("Failed to build window"));
+
+    window.set_cursor_hittest(false).expect("Failed to set window to be passthrough.");
+    window.set_outer_position(LogicalPosition::new(-1000, -1000));
+
+    let context = softbuffer::Context::new(window.clone()).expect("Failed to create graphics context.");
+    let mut surface = Surface::new(&context, window.clone()).expect("Failed to create graphics surface.");
+
+    surface.resize(NonZeroU32::new(CURSOR_SIZE).unwrap(), NonZeroU32::new(CURSOR_SIZE).unwrap()).unwrap();
+
+    TutorWindow {
+        window,
+        surface,
+        state : UserState::Idle,
+        frame : 0,
+        // Nothing's been drawn yet, so the first draw always puts the cursor in.
+        image_version : u32::MAX,
+    }
+}
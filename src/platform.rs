@@ -0,0 +1,45 @@
+use winit::event_loop::EventLoop;
+use winit::window::{Icon, WindowBuilder};
+#[cfg(windows)]
+use winit::platform::windows::WindowBuilderExtWindows;
+#[cfg(target_os = "linux")]
+use winit::event_loop::EventLoopBuilder;
+#[cfg(target_os = "linux")]
+use winit::platform::x11::{EventLoopBuilderExtX11, WindowBuilderExtX11, XWindowType};
+
+// Matches the desktop file on Linux, so the tutor window gets the right icon in docks and task switchers.
+#[cfg(target_os = "linux")]
+const APPLICATION_NAME : &str = "laser_pointer";
+
+// Wayland won't let windows place themselves, so the overlay goes through X11 (or XWayland) on Linux.
+pub fn overlay_event_loop() -> EventLoop<()> {
+    #[cfg(target_os = "linux")]
+    let event_loop = EventLoopBuilder::new().with_x11().build();
+    #[cfg(not(target_os = "linux"))]
+    let event_loop = EventLoop::new();
+    event_loop.expect("Failed to build event loop, on Linux the overlay needs X11 or XWayland")
+}
+
+// Keeps the cursor windows out of the taskbar and the window manager's way.
+pub fn overlay_window(builder : WindowBuilder) -> WindowBuilder {
+    #[cfg(windows)]
+    let builder = builder.with_skip_taskbar(true);
+    // Override redirect windows aren't managed at all, so they don't show up in taskbars, get decorated or get moved around.
+    #[cfg(target_os = "linux")]
+    let builder = builder.with_name(APPLICATION_NAME, APPLICATION_NAME)
+        .with_override_redirect(true)
+        .with_x11_window_type(vec![XWindowType::Notification]);
+    builder
+}
+
+// X11 takes the window icon for its taskbar and Wayland looks the name up in desktop files, only Windows wants a separate one.
+pub fn tutor_window(builder : WindowBuilder, taskbar_icon : Icon) -> WindowBuilder {
+    #[cfg(windows)]
+    let builder = builder.with_taskbar_icon(Some(taskbar_icon));
+    #[cfg(not(windows))]
+    drop(taskbar_icon);
+    // The X11 and Wayland name end up in the same place, setting either one covers both.
+    #[cfg(target_os = "linux")]
+    let builder = builder.with_name(APPLICATION_NAME, APPLICATION_NAME);
+    builder
+}
@@ -0,0 +1,657 @@
+use std::error::Error;
+use std::fmt;
+use crate::shared::{Animation, Annotation, Callout, Shape, ShapeKind, ShapeUpdate, AssetChunk, AssetHash, AssetKind, AssetManifest, AssetProgress, AssetStatus, Features, Frame, Hello, HelloReply, MousePosition, StateSample, StateUpdate, StrokeStyle, UserAnimationStates, UserPacket, UserState, MAX_CURSOR_FRAMES};
+
+// Every packet starts with the protocol version and the message type, followed by that message's payload.
+// Numbers are little endian, positions are quantized to u16 across the window/monitor.
+// The hello messages keep the same layout in every version, so mismatched builds can still explain themselves.
+pub const PROTOCOL_VERSION : u8 = 5;
+
+const MESSAGE_STATE : u8 = 0;
+const MESSAGE_ASSET_MANIFEST : u8 = 2;
+const MESSAGE_HELLO : u8 = 3;
+const MESSAGE_HELLO_REPLY : u8 = 4;
+const MESSAGE_ASSET_CHUNK : u8 = 5;
+const MESSAGE_ASSET_STATUS : u8 = 6;
+const MESSAGE_HEARTBEAT : u8 = 7;
+const MESSAGE_GOODBYE : u8 = 8;
+const MESSAGE_ANNOTATION : u8 = 9;
+const MESSAGE_SHAPE : u8 = 10;
+const MESSAGE_CALLOUT : u8 = 11;
+
+const STATE_IDLE : u8 = 0;
+const STATE_VISIBLE : u8 = 1;
+const STATE_FLASHING : u8 = 2;
+
+const ANNOTATION_BEGIN : u8 = 0;
+const ANNOTATION_EXTEND : u8 = 1;
+const ANNOTATION_CLEAR : u8 = 2;
+const ANNOTATION_UNDO : u8 = 3;
+const ANNOTATION_REDO : u8 = 4;
+const ANNOTATION_DONE : u8 = 5;
+const ANNOTATION_CLEAR_ALL : u8 = 6;
+
+const SHAPE_PLACE : u8 = 0;
+const SHAPE_REMOVE : u8 = 1;
+
+const SHAPE_ARROW : u8 = 0;
+const SHAPE_RECTANGLE : u8 = 1;
+const SHAPE_ELLIPSE : u8 = 2;
+const SHAPE_HIGHLIGHT : u8 = 3;
+
+const ASSET_CURSOR_SHEET : u8 = 0;
+const ASSET_ANIMATION_SET : u8 = 1;
+
+const ASSET_RECEIVING : u8 = 0;
+const ASSET_COMPLETE : u8 = 1;
+const ASSET_FAILED : u8 = 2;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownMessage(u8),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated => write!(f, "packet ended early"),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {} (we speak {})", version, PROTOCOL_VERSION),
+            ProtocolError::UnknownMessage(message) => write!(f, "unknown message type {}", message),
+            ProtocolError::Malformed(reason) => write!(f, "malformed packet, {}", reason),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+pub fn encode(packet : &UserPacket) -> Vec<u8> {
+    let mut writer = Writer { bytes : vec![PROTOCOL_VERSION] };
+    match packet {
+        UserPacket::State(update) => {
+            writer.u8(MESSAGE_STATE);
+            writer.u32(update.sequence);
+            writer.samples(&update.samples);
+        }
+        UserPacket::AssetManifest(manifest) => {
+            writer.u8(MESSAGE_ASSET_MANIFEST);
+            writer.bytes.extend_from_slice(&manifest.hash);
+            writer.u8(match manifest.kind {
+                AssetKind::CursorSheet => ASSET_CURSOR_SHEET,
+                AssetKind::AnimationSet => ASSET_ANIMATION_SET,
+            });
+            writer.u32(manifest.total_size);
+            writer.u32(manifest.frame_count);
+        }
+        UserPacket::AssetChunk(chunk) => {
+            writer.u8(MESSAGE_ASSET_CHUNK);
+            writer.bytes.extend_from_slice(&chunk.hash);
+            writer.u32(chunk.offset);
+            writer.bytes.extend_from_slice(&chunk.data);
+        }
+        UserPacket::AssetStatus(status) => {
+            writer.u8(MESSAGE_ASSET_STATUS);
+            writer.bytes.extend_from_slice(&status.hash);
+            writer.u32(status.received);
+            match &status.progress {
+                AssetProgress::Receiving => writer.u8(ASSET_RECEIVING),
+                AssetProgress::Complete => writer.u8(ASSET_COMPLETE),
+                AssetProgress::Failed(reason) => {
+                    writer.u8(ASSET_FAILED);
+                    writer.string(reason);
+                }
+            }
+        }
+        UserPacket::Annotation(annotation) => {
+            writer.u8(MESSAGE_ANNOTATION);
+            writer.annotation(annotation);
+        }
+        UserPacket::Shape(update) => {
+            writer.u8(MESSAGE_SHAPE);
+            match update {
+                ShapeUpdate::Place(shape) => {
+                    writer.u8(SHAPE_PLACE);
+                    writer.shape(shape);
+                }
+                ShapeUpdate::Remove(id) => {
+                    writer.u8(SHAPE_REMOVE);
+                    writer.u32(*id);
+                }
+            }
+        }
+        UserPacket::Callout(callout) => {
+            writer.u8(MESSAGE_CALLOUT);
+            writer.u32(callout.id);
+            writer.position(&callout.position);
+            writer.u8(callout.size);
+            writer.bytes.extend_from_slice(&callout.color);
+            writer.string(&callout.text);
+        }
+        UserPacket::Heartbeat => writer.u8(MESSAGE_HEARTBEAT),
+        UserPacket::Goodbye => writer.u8(MESSAGE_GOODBYE),
+        UserPacket::Hello(hello) => {
+            writer.u8(MESSAGE_HELLO);
+            writer.u8(hello.protocol_version);
+            writer.string(&hello.app_version);
+            writer.string(&hello.name);
+            writer.u32(hello.features.0);
+            writer.string(&hello.secret);
+            writer.string(&hello.install);
+        }
+        UserPacket::HelloReply(reply) => {
+            writer.u8(MESSAGE_HELLO_REPLY);
+            writer.u8(reply.accepted as u8);
+            writer.u8(reply.protocol_version);
+            writer.string(&reply.app_version);
+            writer.u32(reply.features.0);
+            writer.string(&reply.reason);
+        }
+    }
+    writer.bytes
+}
+
+pub fn decode(bytes : &[u8]) -> Result<UserPacket, ProtocolError> {
+    let mut reader = Reader { bytes, offset : 0 };
+    let version = reader.u8()?;
+    // Fields may get appended to the hello messages later on, so they're allowed to have leftovers.
+    let packet = match reader.u8()? {
+        MESSAGE_HELLO => return Ok(UserPacket::Hello(Hello {
+            protocol_version : reader.u8()?,
+            app_version : reader.string()?,
+            name : reader.string()?,
+            features : Features(reader.u32()?),
+            secret : if reader.is_empty() { String::new() } else { reader.string()? },
+            install : if reader.is_empty() { String::new() } else { reader.string()? },
+        })),
+        MESSAGE_HELLO_REPLY => return Ok(UserPacket::HelloReply(HelloReply {
+            accepted : reader.u8()? != 0,
+            protocol_version : reader.u8()?,
+            app_version : reader.string()?,
+            features : Features(reader.u32()?),
+            reason : reader.string()?,
+        })),
+        _ if version != PROTOCOL_VERSION => return Err(ProtocolError::UnsupportedVersion(version)),
+        MESSAGE_STATE => UserPacket::State(StateUpdate {
+            sequence : reader.u32()?,
+            samples : reader.samples()?,
+        }),
+        MESSAGE_ASSET_MANIFEST => UserPacket::AssetManifest(AssetManifest {
+            hash : reader.hash()?,
+            kind : match reader.u8()? {
+                ASSET_CURSOR_SHEET => AssetKind::CursorSheet,
+                ASSET_ANIMATION_SET => AssetKind::AnimationSet,
+                _ => return Err(ProtocolError::Malformed("unknown asset kind")),
+            },
+            total_size : reader.u32()?,
+            frame_count : reader.u32()?,
+        }),
+        MESSAGE_ASSET_CHUNK => UserPacket::AssetChunk(AssetChunk {
+            hash : reader.hash()?,
+            offset : reader.u32()?,
+            data : reader.rest().to_vec(),
+        }),
+        MESSAGE_ASSET_STATUS => UserPacket::AssetStatus(AssetStatus {
+            hash : reader.hash()?,
+            received : reader.u32()?,
+            progress : match reader.u8()? {
+                ASSET_RECEIVING => AssetProgress::Receiving,
+                ASSET_COMPLETE => AssetProgress::Complete,
+                ASSET_FAILED => AssetProgress::Failed(reader.string()?),
+                _ => return Err(ProtocolError::Malformed("unknown asset progress")),
+            },
+        }),
+        MESSAGE_ANNOTATION => UserPacket::Annotation(reader.annotation()?),
+        MESSAGE_SHAPE => UserPacket::Shape(match reader.u8()? {
+            SHAPE_PLACE => ShapeUpdate::Place(reader.shape()?),
+            SHAPE_REMOVE => ShapeUpdate::Remove(reader.u32()?),
+            _ => return Err(ProtocolError::Malformed("unknown shape update")),
+        }),
+        MESSAGE_CALLOUT => UserPacket::Callout(Callout {
+            id : reader.u32()?,
+            position : reader.position()?,
+            size : reader.u8()?,
+            color : reader.take(3)?.try_into().unwrap(),
+            text : reader.string()?,
+        }),
+        MESSAGE_HEARTBEAT => UserPacket::Heartbeat,
+        MESSAGE_GOODBYE => UserPacket::Goodbye,
+        message => return Err(ProtocolError::UnknownMessage(message)),
+    };
+    if !reader.rest().is_empty() {
+        return Err(ProtocolError::Malformed("trailing bytes"));
+    }
+    Ok(packet)
+}
+
+// Animation sets travel as assets, so they get their own encoding outside of a packet.
+pub fn encode_animation_set(set : &UserAnimationStates) -> Result<Vec<u8>, ProtocolError> {
+    let mut writer = Writer { bytes : Vec::new() };
+    for animation in [&set.idle, &set.visible, &set.flashing] {
+        writer.animation(animation)?;
+    }
+    Ok(writer.bytes)
+}
+
+pub fn decode_animation_set(bytes : &[u8]) -> Result<UserAnimationStates, ProtocolError> {
+    let mut reader = Reader { bytes, offset : 0 };
+    let set = UserAnimationStates {
+        idle : reader.animation()?,
+        visible : reader.animation()?,
+        flashing : reader.animation()?,
+    };
+    if !reader.rest().is_empty() {
+        return Err(ProtocolError::Malformed("trailing bytes"));
+    }
+    Ok(set)
+}
+
+struct Writer {
+    bytes : Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value : u8) {
+        self.bytes.push(value);
+    }
+    fn u16(&mut self, value : u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    fn u32(&mut self, value : u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    fn f32(&mut self, value : f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    fn string(&mut self, value : &str) {
+        let mut length = value.len().min(u16::MAX as usize);
+        while !value.is_char_boundary(length) {
+            length -= 1;
+        }
+        self.u16(length as u16);
+        self.bytes.extend_from_slice(&value.as_bytes()[..length]);
+    }
+    fn position(&mut self, position : &MousePosition) {
+        self.u16(quantize(position.x));
+        self.u16(quantize(position.y));
+    }
+    fn state(&mut self, state : &UserState) {
+        match state {
+            UserState::Idle => self.u8(STATE_IDLE),
+            UserState::Visible(position) => {
+                self.u8(STATE_VISIBLE);
+                self.position(position);
+            }
+            UserState::Flashing(position) => {
+                self.u8(STATE_FLASHING);
+                self.position(position);
+            }
+        }
+    }
+    // The first timestamp is written in full, the rest as u16 milliseconds after it.
+    fn samples(&mut self, samples : &[StateSample]) {
+        let start = samples.first().map(|sample| sample.timestamp).unwrap_or(0);
+        self.u8(samples.len().min(u8::MAX as usize) as u8);
+        self.u32(start);
+        for sample in samples.iter().take(u8::MAX as usize) {
+            self.u16(sample.timestamp.saturating_sub(start).min(u16::MAX as u32) as u16);
+            self.state(&sample.state);
+        }
+    }
+    fn annotation(&mut self, annotation : &Annotation) {
+        match annotation {
+            Annotation::Begin { stroke, style, point } => {
+                self.u8(ANNOTATION_BEGIN);
+                self.u32(*stroke);
+                self.style(style);
+                self.position(point);
+            }
+            Annotation::Extend { stroke, points } => {
+                self.u8(ANNOTATION_EXTEND);
+                self.u32(*stroke);
+                self.u16(points.len().min(u16::MAX as usize) as u16);
+                for point in points.iter().take(u16::MAX as usize) {
+                    self.position(point);
+                }
+            }
+            Annotation::Clear => self.u8(ANNOTATION_CLEAR),
+            Annotation::Undo => self.u8(ANNOTATION_UNDO),
+            Annotation::Redo => self.u8(ANNOTATION_REDO),
+            Annotation::Done => self.u8(ANNOTATION_DONE),
+            Annotation::ClearAll => self.u8(ANNOTATION_CLEAR_ALL),
+        }
+    }
+    fn style(&mut self, style : &StrokeStyle) {
+        self.bytes.extend_from_slice(&style.color);
+        self.u8(style.width);
+    }
+    fn shape(&mut self, shape : &Shape) {
+        self.u32(shape.id);
+        self.u8(match shape.kind {
+            ShapeKind::Arrow => SHAPE_ARROW,
+            ShapeKind::Rectangle => SHAPE_RECTANGLE,
+            ShapeKind::Ellipse => SHAPE_ELLIPSE,
+            ShapeKind::Highlight => SHAPE_HIGHLIGHT,
+        });
+        self.style(&shape.style);
+        self.position(&shape.from);
+        self.position(&shape.to);
+    }
+    fn animation(&mut self, animation : &Animation) -> Result<(), ProtocolError> {
+        let count = u16::try_from(animation.frames.len()).map_err(|_| ProtocolError::Malformed("an animation can't have more than 65535 frames"))?;
+        self.u16(count);
+        for frame in &animation.frames {
+            if frame.index >= MAX_CURSOR_FRAMES {
+                return Err(ProtocolError::Malformed("frame index past the largest cursor sheet"));
+            }
+            self.u32(frame.index);
+            self.f32(frame.duration);
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes : &'a [u8],
+    offset : usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, amount : usize) -> Result<&'a [u8], ProtocolError> {
+        if self.bytes.len() - self.offset < amount {
+            return Err(ProtocolError::Truncated);
+        }
+        let taken = &self.bytes[self.offset..self.offset + amount];
+        self.offset += amount;
+        Ok(taken)
+    }
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.offset..];
+        self.offset = self.bytes.len();
+        rest
+    }
+    fn is_empty(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn f32(&mut self) -> Result<f32, ProtocolError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn hash(&mut self) -> Result<AssetHash, ProtocolError> {
+        Ok(self.take(32)?.try_into().unwrap())
+    }
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let length = self.u16()? as usize;
+        match std::str::from_utf8(self.take(length)?) {
+            Ok(value) => Ok(value.to_string()),
+            Err(_) => Err(ProtocolError::Malformed("string isn't utf-8")),
+        }
+    }
+    fn position(&mut self) -> Result<MousePosition, ProtocolError> {
+        Ok(MousePosition {
+            x : dequantize(self.u16()?),
+            y : dequantize(self.u16()?),
+        })
+    }
+    fn state(&mut self) -> Result<UserState, ProtocolError> {
+        match self.u8()? {
+            STATE_IDLE => Ok(UserState::Idle),
+            STATE_VISIBLE => Ok(UserState::Visible(self.position()?)),
+            STATE_FLASHING => Ok(UserState::Flashing(self.position()?)),
+            _ => Err(ProtocolError::Malformed("unknown pointer state")),
+        }
+    }
+    fn samples(&mut self) -> Result<Vec<StateSample>, ProtocolError> {
+        let count = self.u8()?;
+        if count == 0 {
+            return Err(ProtocolError::Malformed("state update without samples"));
+        }
+        let start = self.u32()?;
+        let mut samples = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            samples.push(StateSample {
+                timestamp : start.wrapping_add(self.u16()? as u32),
+                state : self.state()?,
+            });
+        }
+        Ok(samples)
+    }
+    fn annotation(&mut self) -> Result<Annotation, ProtocolError> {
+        match self.u8()? {
+            ANNOTATION_BEGIN => Ok(Annotation::Begin {
+                stroke : self.u32()?,
+                style : self.style()?,
+                point : self.position()?,
+            }),
+            ANNOTATION_EXTEND => {
+                let stroke = self.u32()?;
+                let count = self.u16()?;
+                let mut points = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    points.push(self.position()?);
+                }
+                Ok(Annotation::Extend { stroke, points })
+            }
+            ANNOTATION_CLEAR => Ok(Annotation::Clear),
+            ANNOTATION_UNDO => Ok(Annotation::Undo),
+            ANNOTATION_REDO => Ok(Annotation::Redo),
+            ANNOTATION_DONE => Ok(Annotation::Done),
+            ANNOTATION_CLEAR_ALL => Ok(Annotation::ClearAll),
+            _ => Err(ProtocolError::Malformed("unknown annotation")),
+        }
+    }
+    fn style(&mut self) -> Result<StrokeStyle, ProtocolError> {
+        Ok(StrokeStyle {
+            color : self.take(3)?.try_into().unwrap(),
+            width : self.u8()?,
+        })
+    }
+    fn shape(&mut self) -> Result<Shape, ProtocolError> {
+        Ok(Shape {
+            id : self.u32()?,
+            kind : match self.u8()? {
+                SHAPE_ARROW => ShapeKind::Arrow,
+                SHAPE_RECTANGLE => ShapeKind::Rectangle,
+                SHAPE_ELLIPSE => ShapeKind::Ellipse,
+                SHAPE_HIGHLIGHT => ShapeKind::Highlight,
+                _ => return Err(ProtocolError::Malformed("unknown shape")),
+            },
+            style : self.style()?,
+            from : self.position()?,
+            to : self.position()?,
+        })
+    }
+    fn animation(&mut self) -> Result<Animation, ProtocolError> {
+        let count = self.u16()?;
+        if count == 0 {
+            return Err(ProtocolError::Malformed("animation without frames"));
+        }
+        let mut frames = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let index = self.u32()?;
+            if index >= MAX_CURSOR_FRAMES {
+                return Err(ProtocolError::Malformed("frame index past the largest cursor sheet"));
+            }
+            let duration = self.f32()?;
+            if duration.is_nan() || duration <= 0.0 {
+                return Err(ProtocolError::Malformed("frame duration must be positive"));
+            }
+            frames.push(Frame { index, duration });
+        }
+        Ok(Animation { frames })
+    }
+}
+
+fn quantize(value : f32) -> u16 {
+    (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+}
+
+fn dequantize(value : u16) -> f32 {
+    value as f32 / u16::MAX as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::shared::{Annotation, AssetChunk, AssetKind, AssetManifest, AssetProgress, AssetStatus, Callout, Frame, Hello, HelloReply, MousePosition, Shape, ShapeKind, ShapeUpdate, StateSample, StateUpdate, StrokeStyle, UserAnimationStates, UserPacket, UserState, MAX_CURSOR_FRAMES};
+    use super::{decode, decode_animation_set, encode, encode_animation_set, ProtocolError, MESSAGE_ANNOTATION, MESSAGE_SHAPE, MESSAGE_STATE, PROTOCOL_VERSION};
+
+    // Corners survive quantization exactly, anything in between comes back slightly off.
+    const CORNER : MousePosition = MousePosition { x : 0.0, y : 1.0 };
+    const STYLE : StrokeStyle = StrokeStyle { color : [255, 48, 48], width : 4 };
+
+    fn samples(count : u32) -> Vec<StateSample> {
+        (0..count).map(|index| StateSample {
+            timestamp : 1000 + index * 8,
+            state : match index % 3 {
+                0 => UserState::Idle,
+                1 => UserState::Visible(CORNER),
+                _ => UserState::Flashing(CORNER),
+            },
+        }).collect()
+    }
+
+    // One of every kind of packet.
+    fn packets() -> Vec<UserPacket> {
+        let shape = Shape { id : 3, kind : ShapeKind::Ellipse, style : STYLE, from : CORNER, to : MousePosition { x : 1.0, y : 0.0 } };
+        vec![
+            UserPacket::State(StateUpdate { sequence : 7, samples : samples(3) }),
+            UserPacket::Hello(Hello::new("Ms. Frizzle".to_string(), "4921".to_string(), "0123456789abcdef".to_string())),
+            UserPacket::HelloReply(HelloReply::accept()),
+            UserPacket::HelloReply(HelloReply::reject("No thanks.".to_string())),
+            UserPacket::AssetManifest(AssetManifest { hash : [1; 32], kind : AssetKind::CursorSheet, total_size : 4096, frame_count : 2 }),
+            UserPacket::AssetManifest(AssetManifest { hash : [2; 32], kind : AssetKind::AnimationSet, total_size : 12, frame_count : 0 }),
+            UserPacket::AssetChunk(AssetChunk { hash : [3; 32], offset : 1024, data : vec![9; 100] }),
+            UserPacket::AssetStatus(AssetStatus { hash : [4; 32], received : 512, progress : AssetProgress::Receiving }),
+            UserPacket::AssetStatus(AssetStatus { hash : [4; 32], received : 4096, progress : AssetProgress::Complete }),
+            UserPacket::AssetStatus(AssetStatus { hash : [4; 32], received : 0, progress : AssetProgress::Failed("too big".to_string()) }),
+            UserPacket::Annotation(Annotation::Begin { stroke : 1, style : STYLE, point : CORNER }),
+            UserPacket::Annotation(Annotation::Extend { stroke : 1, points : vec![CORNER; 5] }),
+            UserPacket::Annotation(Annotation::Clear),
+            UserPacket::Annotation(Annotation::Undo),
+            UserPacket::Annotation(Annotation::Redo),
+            UserPacket::Annotation(Annotation::Done),
+            UserPacket::Annotation(Annotation::ClearAll),
+            UserPacket::Shape(ShapeUpdate::Place(shape)),
+            UserPacket::Shape(ShapeUpdate::Remove(3)),
+            UserPacket::Callout(Callout { id : 4, position : CORNER, text : "héllo ✏️".to_string(), size : 24, color : [1, 2, 3] }),
+            UserPacket::Heartbeat,
+            UserPacket::Goodbye,
+        ]
+    }
+
+    #[test]
+    fn every_packet_round_trips() {
+        for packet in packets() {
+            assert!(decode(&encode(&packet)).unwrap() == packet);
+        }
+        let set = UserAnimationStates::new();
+        assert_eq!(decode_animation_set(&encode_animation_set(&set).unwrap()).unwrap(), set);
+    }
+
+    #[test]
+    fn truncated_packets_are_errors() {
+        for packet in packets() {
+            let bytes = encode(&packet);
+            for length in 0..bytes.len() {
+                let result = decode(&bytes[..length]);
+                // Hellos may be missing trailing fields, and a chunk's data is whatever's left, so those can still decode.
+                if !matches!(packet, UserPacket::Hello(_) | UserPacket::HelloReply(_) | UserPacket::AssetChunk(_)) {
+                    assert!(result.is_err(), "a {} byte prefix of a {} byte packet decoded", length, bytes.len());
+                }
+            }
+        }
+        let set = encode_animation_set(&UserAnimationStates::new()).unwrap();
+        for length in 0..set.len() {
+            assert!(decode_animation_set(&set[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn oversized_input_is_capped_or_rejected() {
+        // More than fit in a packet are cut down to what the count can say.
+        let update = UserPacket::State(StateUpdate { sequence : 1, samples : samples(300) });
+        match decode(&encode(&update)).unwrap() {
+            UserPacket::State(update) => assert_eq!(update.samples.len(), u8::MAX as usize),
+            _ => panic!("decoded as something other than a state update"),
+        }
+        let callout = Callout { id : 1, position : CORNER, text : "é".repeat(40_000), size : 24, color : [0; 3] };
+        match decode(&encode(&UserPacket::Callout(callout))).unwrap() {
+            UserPacket::Callout(callout) => assert!(callout.text.len() <= u16::MAX as usize),
+            _ => panic!("decoded as something other than a callout"),
+        }
+        // Lengths claiming more than was sent.
+        let mut points = vec![PROTOCOL_VERSION, MESSAGE_ANNOTATION, super::ANNOTATION_EXTEND, 1, 0, 0, 0];
+        points.extend_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(decode(&points), Err(ProtocolError::Truncated)));
+        // Version, message type and the tutor's protocol version come before the first string.
+        let mut hello = encode(&UserPacket::Hello(Hello::new(String::new(), String::new(), String::new())));
+        hello.truncate(3);
+        hello.extend_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(decode(&hello), Err(ProtocolError::Truncated)));
+        // The frame count can't say this many, so it's refused rather than sent garbled.
+        let mut set = UserAnimationStates::new();
+        set.visible.frames = vec![Frame { index : 0, duration : 0.1 }; u16::MAX as usize + 1];
+        assert!(matches!(encode_animation_set(&set), Err(ProtocolError::Malformed(_))));
+        // Same for a frame no cursor sheet could have, on either end.
+        let mut set = UserAnimationStates::new();
+        set.idle.frames[0].index = MAX_CURSOR_FRAMES;
+        assert!(matches!(encode_animation_set(&set), Err(ProtocolError::Malformed(_))));
+        set.idle.frames[0].index = 0;
+        let mut bytes = encode_animation_set(&set).unwrap();
+        bytes[2..6].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decode_animation_set(&bytes), Err(ProtocolError::Malformed(_))));
+    }
+
+    #[test]
+    fn unknown_kinds_are_rejected() {
+        let mut wrong_version = encode(&UserPacket::Heartbeat);
+        wrong_version[0] = PROTOCOL_VERSION + 1;
+        assert!(matches!(decode(&wrong_version), Err(ProtocolError::UnsupportedVersion(_))));
+        // Hellos still make it through, so mismatched builds can explain themselves.
+        let mut hello = encode(&UserPacket::Hello(Hello::new("old".to_string(), String::new(), String::new())));
+        hello[0] = PROTOCOL_VERSION + 1;
+        assert!(matches!(decode(&hello), Ok(UserPacket::Hello(_))));
+        assert!(matches!(decode(&[PROTOCOL_VERSION, 200]), Err(ProtocolError::UnknownMessage(200))));
+        assert!(matches!(decode(&[PROTOCOL_VERSION, MESSAGE_ANNOTATION, 200]), Err(ProtocolError::Malformed(_))));
+        assert!(matches!(decode(&[PROTOCOL_VERSION, MESSAGE_SHAPE, 200]), Err(ProtocolError::Malformed(_))));
+        let mut state = vec![PROTOCOL_VERSION, MESSAGE_STATE, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        state.push(200);
+        assert!(matches!(decode(&state), Err(ProtocolError::Malformed(_))));
+        let mut shape = encode(&UserPacket::Shape(ShapeUpdate::Place(Shape { id : 1, kind : ShapeKind::Arrow, style : STYLE, from : CORNER, to : CORNER })));
+        shape[7] = 200;
+        assert!(matches!(decode(&shape), Err(ProtocolError::Malformed(_))));
+        let mut manifest = encode(&UserPacket::AssetManifest(AssetManifest { hash : [0; 32], kind : AssetKind::CursorSheet, total_size : 1, frame_count : 1 }));
+        manifest[34] = 200;
+        assert!(matches!(decode(&manifest), Err(ProtocolError::Malformed(_))));
+        let mut trailing = encode(&UserPacket::Goodbye);
+        trailing.push(0);
+        assert!(matches!(decode(&trailing), Err(ProtocolError::Malformed(_))));
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        let mut rng = StdRng::seed_from_u64(4921);
+        for _ in 0..20_000 {
+            let length = rng.gen_range(0..64);
+            let mut bytes : Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            // Mostly aim at real message types, or almost everything stops at the version check.
+            if bytes.len() >= 2 && rng.gen_bool(0.9) {
+                bytes[0] = PROTOCOL_VERSION;
+                bytes[1] = rng.gen_range(0..12);
+            }
+            let _ = decode(&bytes);
+            let _ = decode_animation_set(&bytes);
+        }
+    }
+}
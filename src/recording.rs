@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::time::Instant;
+use crate::assets::chunks_from;
+use crate::framing::{read_frame, write_frame};
+use crate::protocol;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::shared::{AssetManifest, UserPacket};
+use crate::transport::PeerId;
+
+// A recording is a series of frames, the first holds this followed by the protocol version.
+// Every frame after it is a u32 millisecond timestamp, the sender as a u16 length prefixed string, and an encoded packet.
+// Assets are stored as the manifest and chunks that would have delivered them, however they actually arrived.
+const MAGIC : &[u8] = b"laser_pointer recording";
+
+pub struct Recorder {
+    file : BufWriter<File>,
+    started : Instant,
+}
+
+pub struct Entry {
+    pub time : u32,
+    pub sender : String,
+    pub packet : UserPacket,
+}
+
+pub struct Recording {
+    file : BufReader<File>,
+}
+
+impl Recorder {
+    pub fn create(path : &str) -> std::io::Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut header = MAGIC.to_vec();
+        header.push(PROTOCOL_VERSION);
+        write_frame(&mut file, &header)?;
+        Ok(Recorder { file, started : Instant::now() })
+    }
+
+    pub fn packet(&mut self, peer : PeerId, packet : &UserPacket) {
+        let sender = peer.to_string();
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&(self.started.elapsed().as_millis() as u32).to_le_bytes());
+        frame.extend_from_slice(&(sender.len() as u16).to_le_bytes());
+        frame.extend_from_slice(sender.as_bytes());
+        frame.extend_from_slice(&protocol::encode(packet));
+        // Losing the recording shouldn't take the session down with it.
+        if let Err(err) = write_frame(&mut self.file, &frame).and_then(|_| self.file.flush()) {
+            println!("Failed to write to the recording: {}", err);
+        }
+    }
+
+    pub fn asset(&mut self, peer : PeerId, manifest : &AssetManifest, bytes : &[u8]) {
+        self.packet(peer, &UserPacket::AssetManifest(manifest.clone()));
+        for chunk in chunks_from(manifest.hash, bytes, 0) {
+            self.packet(peer, &UserPacket::AssetChunk(chunk));
+        }
+    }
+}
+
+impl Recording {
+    pub fn open(path : &str) -> Result<Recording, Box<dyn Error>> {
+        let mut file = BufReader::new(File::open(path)?);
+        let header = read_frame(&mut file)?;
+        if !header.starts_with(MAGIC) || header.len() != MAGIC.len() + 1 {
+            return Err(format!("{} isn't a laser_pointer recording", path).into());
+        }
+        let version = header[MAGIC.len()];
+        if version != PROTOCOL_VERSION {
+            return Err(format!("{} was recorded with protocol {}, we're running protocol {}", path, version, PROTOCOL_VERSION).into());
+        }
+        Ok(Recording { file })
+    }
+
+    // None once the recording ends, a recording that got cut off just ends early.
+    pub fn next(&mut self) -> Option<Result<Entry, Box<dyn Error>>> {
+        let frame = match read_frame(&mut self.file) {
+            Ok(frame) => frame,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return None,
+            Err(err) => return Some(Err(err.into())),
+        };
+        Some(parse_entry(&frame))
+    }
+}
+
+fn parse_entry(frame : &[u8]) -> Result<Entry, Box<dyn Error>> {
+    if frame.len() < 6 {
+        return Err("truncated entry".into());
+    }
+    let time = u32::from_le_bytes(frame[..4].try_into().unwrap());
+    let sender_length = u16::from_le_bytes(frame[4..6].try_into().unwrap()) as usize;
+    if frame.len() < 6 + sender_length {
+        return Err("truncated entry".into());
+    }
+    let sender = String::from_utf8(frame[6..6 + sender_length].to_vec())?;
+    let packet = protocol::decode(&frame[6 + sender_length..])?;
+    Ok(Entry { time, sender, packet })
+}
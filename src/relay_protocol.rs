@@ -0,0 +1,111 @@
+// Messages spoken between the app and laser_pointer-relay, each one sent as a single frame.
+// Every connection to the relay gets a random id that doubles as its join code.
+
+const CODE_ALPHABET : &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+const CODE_LENGTH : usize = 6;
+pub const CODE_LIMIT : u32 = 1 << (5 * CODE_LENGTH);
+
+#[derive(PartialEq,Debug,Clone)]
+pub enum RelayMessage {
+    Welcome(u32),
+    Register,
+    Registered(u32),
+    Join(u32),
+    Joined(u32),
+    PeerJoined(u32),
+    PeerLeft(u32),
+    Data(u32, Vec<u8>),
+    Error(String),
+    Leave(u32),
+}
+
+impl RelayMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (tag, id, rest) : (u8, u32, &[u8]) = match self {
+            RelayMessage::Welcome(id) => (0, *id, &[]),
+            RelayMessage::Register => (1, 0, &[]),
+            RelayMessage::Registered(id) => (2, *id, &[]),
+            RelayMessage::Join(id) => (3, *id, &[]),
+            RelayMessage::Joined(id) => (4, *id, &[]),
+            RelayMessage::PeerJoined(id) => (5, *id, &[]),
+            RelayMessage::PeerLeft(id) => (6, *id, &[]),
+            RelayMessage::Data(id, data) => (7, *id, data),
+            RelayMessage::Error(message) => (8, 0, message.as_bytes()),
+            RelayMessage::Leave(id) => (9, *id, &[]),
+        };
+        let mut bytes = Vec::with_capacity(5 + rest.len());
+        bytes.push(tag);
+        bytes.extend_from_slice(&id.to_le_bytes());
+        bytes.extend_from_slice(rest);
+        bytes
+    }
+
+    pub fn decode(bytes : &[u8]) -> Option<RelayMessage> {
+        if bytes.len() < 5 {
+            return None;
+        }
+        let id = u32::from_le_bytes(bytes[1..5].try_into().unwrap());
+        let rest = &bytes[5..];
+        Some(match bytes[0] {
+            0 => RelayMessage::Welcome(id),
+            1 => RelayMessage::Register,
+            2 => RelayMessage::Registered(id),
+            3 => RelayMessage::Join(id),
+            4 => RelayMessage::Joined(id),
+            5 => RelayMessage::PeerJoined(id),
+            6 => RelayMessage::PeerLeft(id),
+            7 => RelayMessage::Data(id, rest.to_vec()),
+            8 => RelayMessage::Error(String::from_utf8_lossy(rest).to_string()),
+            9 => RelayMessage::Leave(id),
+            _ => return None,
+        })
+    }
+}
+
+// Codes look like KX7-42Q, the alphabet skips 0, 1, I and O so they can be read out loud.
+pub fn format_code(id : u32) -> String {
+    let mut code = String::new();
+    for index in 0..CODE_LENGTH {
+        if index == CODE_LENGTH / 2 {
+            code.push('-');
+        }
+        let shift = 5 * (CODE_LENGTH - 1 - index);
+        code.push(CODE_ALPHABET[((id >> shift) & 31) as usize] as char);
+    }
+    code
+}
+
+pub fn parse_code(code : &str) -> Option<u32> {
+    let mut id = 0;
+    let mut length = 0;
+    for character in code.chars().filter(|character| *character != '-' && !character.is_whitespace()) {
+        // Casting anything wider would wrap it onto a real letter.
+        if !character.is_ascii() {
+            return None;
+        }
+        let character = character.to_ascii_uppercase() as u8;
+        let value = CODE_ALPHABET.iter().position(|letter| *letter == character)?;
+        id = (id << 5) | value as u32;
+        length += 1;
+    }
+    if length != CODE_LENGTH {
+        return None;
+    }
+    Some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip_and_reject_lookalikes() {
+        let id = 123_456 % CODE_LIMIT;
+        let code = format_code(id);
+        assert_eq!(parse_code(&code), Some(id));
+        assert_eq!(parse_code(&code.to_lowercase()), Some(id));
+        // U+0132 would come out as '2' if it were cut down to a byte.
+        let wide = code.replacen(|character : char| character.is_ascii_alphanumeric(), "\u{132}", 1);
+        assert_eq!(parse_code(&wide), None);
+    }
+}
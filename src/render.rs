@@ -0,0 +1,516 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use ab_glyph::{point, Font, FontRef, Glyph, PxScale, ScaleFont};
+use image::{DynamicImage, GenericImageView, RgbaImage};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use crate::annotation::Mark;
+use crate::overlay::Tutor;
+use crate::shared::{Callout, MousePosition, Shape, ShapeKind, CURSOR_SIZE};
+
+// How long trails hang around behind a moving cursor.
+pub const TRAIL_DURATION : Duration = Duration::from_millis(200);
+const TRAIL_RADIUS : i32 = 4;
+const TRAIL_OPACITY : f32 = 0.6;
+pub const HIGHLIGHT_OPACITY : f32 = 0.3;
+// Arrow heads are this many times the line width, but never tiny.
+const ARROW_HEAD_SCALE : f32 = 3.0;
+const ARROW_HEAD_MIN : f32 = 10.0;
+const ARROW_HEAD_ANGLE : f32 = 0.45;
+const ELLIPSE_SEGMENTS : usize = 64;
+// Callouts sit on a dark plate so they can be read over anything.
+pub const PLATE_COLOR : [u8; 3] = [24, 24, 24];
+pub const PLATE_OPACITY : f32 = 0.8;
+const MIN_TEXT_SIZE : f32 = 8.0;
+
+static FONT : OnceLock<FontRef<'static>> = OnceLock::new();
+
+// Somewhere the overlay can be drawn, a window's surface or plain memory for tests.
+pub trait RenderTarget {
+    fn size(&self) -> (u32, u32);
+    fn pixel(&self, x : u32, y : u32) -> [u8; 4];
+    fn set_pixel(&mut self, x : u32, y : u32, rgba : [u8; 4]);
+    fn clear(&mut self);
+
+    // Straight alpha over whatever's there, anything off the edge is dropped.
+    fn blend(&mut self, x : i32, y : i32, color : [u8; 3], opacity : f32) {
+        let (width, height) = self.size();
+        if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 || opacity <= 0.0 {
+            return;
+        }
+        let [dr, dg, db, da] = self.pixel(x as u32, y as u32);
+        let mix = |source : u8, destination : u8| (source as f32 * opacity + destination as f32 * (1.0 - opacity)) as u8;
+        let alpha = (opacity * 255.0 + da as f32 * (1.0 - opacity)) as u8;
+        self.set_pixel(x as u32, y as u32, [mix(color[0], dr), mix(color[1], dg), mix(color[2], db), alpha]);
+    }
+}
+
+// A softbuffer buffer, pixels are laid out the same way the per tutor windows write them so both modes look alike.
+pub struct BufferTarget<'a> {
+    pub pixels : &'a mut [u32],
+    pub width : u32,
+    pub height : u32,
+}
+
+// Plain memory, for exports and tests.
+pub struct RgbaTarget {
+    pub image : RgbaImage,
+}
+
+// A callout laid out at its spot on screen, the plate is left, top, right, bottom in pixels.
+pub struct CalloutLayout {
+    pub glyphs : Vec<Glyph>,
+    pub plate : [i32; 4],
+    // Where the first glyph sits on the baseline.
+    pub origin : (f32, f32),
+    pub size : f32,
+}
+
+// Where a monitor sits on the desktop.
+#[derive(Clone, Copy)]
+pub struct Screen {
+    pub origin : PhysicalPosition<i32>,
+    pub size : PhysicalSize<u32>,
+}
+
+impl RenderTarget for BufferTarget<'_> {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+    // softbuffer wants 0xAARRGGBB, alpha only counts on platforms that blend transparent windows.
+    fn pixel(&self, x : u32, y : u32) -> [u8; 4] {
+        let [a, r, g, b] = self.pixels[(y * self.width + x) as usize].to_be_bytes();
+        [r, g, b, a]
+    }
+    fn set_pixel(&mut self, x : u32, y : u32, [r, g, b, a] : [u8; 4]) {
+        self.pixels[(y * self.width + x) as usize] = u32::from_be_bytes([a, r, g, b]);
+    }
+    fn clear(&mut self) {
+        self.pixels.fill(0);
+    }
+}
+
+impl RgbaTarget {
+    pub fn new(width : u32, height : u32) -> RgbaTarget {
+        RgbaTarget { image : RgbaImage::new(width, height) }
+    }
+}
+
+impl RenderTarget for RgbaTarget {
+    fn size(&self) -> (u32, u32) {
+        self.image.dimensions()
+    }
+    fn pixel(&self, x : u32, y : u32) -> [u8; 4] {
+        self.image.get_pixel(x, y).0
+    }
+    fn set_pixel(&mut self, x : u32, y : u32, rgba : [u8; 4]) {
+        self.image.put_pixel(x, y, image::Rgba(rgba));
+    }
+    fn clear(&mut self) {
+        self.image.pixels_mut().for_each(|pixel| pixel.0 = [0; 4]);
+    }
+}
+
+// Draws the part of every tutor that lands on `screen`, positions are across the primary monitor like the per tutor windows.
+pub fn compose(target : &mut dyn RenderTarget, screen : &Screen, primary : &Screen, tutors : &[&Tutor], now : Instant) {
+    compose_ink(target, screen, primary, tutors);
+    let to_screen = |position : &MousePosition| (
+        primary.origin.x + (position.x * primary.size.width as f32) as i32 - screen.origin.x,
+        primary.origin.y + (position.y * primary.size.height as f32) as i32 - screen.origin.y,
+    );
+    for tutor in tutors {
+        let position = match tutor.state.position() {
+            Some(position) => position,
+            None => continue,
+        };
+        for (time, point) in &tutor.trail {
+            let fade = 1.0 - now.saturating_duration_since(*time).as_secs_f32() / TRAIL_DURATION.as_secs_f32();
+            let (x, y) = to_screen(point);
+            dot(target, x, y, tutor.trail_color, fade * TRAIL_OPACITY);
+        }
+        let (x, y) = to_screen(&position);
+        sprite(target, x, y, &tutor.image, tutor.frame);
+    }
+}
+
+// Just the ink, for when the cursors have windows of their own. Ink always sits under the cursors.
+pub fn compose_ink(target : &mut dyn RenderTarget, screen : &Screen, primary : &Screen, tutors : &[&Tutor]) {
+    target.clear();
+    let to_screen = |position : &MousePosition| (
+        (primary.origin.x - screen.origin.x) as f32 + position.x * primary.size.width as f32,
+        (primary.origin.y - screen.origin.y) as f32 + position.y * primary.size.height as f32,
+    );
+    for tutor in tutors {
+        for mark in &tutor.ink.marks {
+            match mark {
+                Mark::Stroke(stroke) => {
+                    let width = stroke.style.width.max(1) as f32;
+                    let mut from = to_screen(&stroke.points[0]);
+                    // A single point still leaves a dot.
+                    segment(target, from, from, stroke.style.color, width);
+                    for point in &stroke.points[1..] {
+                        let to = to_screen(point);
+                        segment(target, from, to, stroke.style.color, width);
+                        from = to;
+                    }
+                }
+                Mark::Shape(shape) => draw_shape(target, shape, to_screen(&shape.from), to_screen(&shape.to)),
+                Mark::Callout(callout) => draw_callout(target, callout, to_screen(&callout.position)),
+            }
+        }
+    }
+}
+
+fn sprite(target : &mut dyn RenderTarget, left : i32, top : i32, image : &DynamicImage, frame : u32) {
+    if frame >= image.width() / CURSOR_SIZE {
+        return;
+    }
+    for y in 0..CURSOR_SIZE {
+        for x in 0..CURSOR_SIZE {
+            let [r, g, b, a] = image.get_pixel(frame * CURSOR_SIZE + x, y).0;
+            target.blend(left + x as i32, top + y as i32, [r, g, b], a as f32 / 255.0);
+        }
+    }
+}
+
+fn dot(target : &mut dyn RenderTarget, centre_x : i32, centre_y : i32, color : [u8; 3], opacity : f32) {
+    for y in -TRAIL_RADIUS..=TRAIL_RADIUS {
+        for x in -TRAIL_RADIUS..=TRAIL_RADIUS {
+            if x * x + y * y <= TRAIL_RADIUS * TRAIL_RADIUS {
+                target.blend(centre_x + x, centre_y + y, color, opacity);
+            }
+        }
+    }
+}
+
+fn draw_shape(target : &mut dyn RenderTarget, shape : &Shape, from : (f32, f32), to : (f32, f32)) {
+    let color = shape.style.color;
+    let width = shape.style.width.max(1) as f32;
+    match shape.kind {
+        ShapeKind::Arrow => {
+            segment(target, from, to, color, width);
+            for barb in arrow_head(from, to, width) {
+                segment(target, to, barb, color, width);
+            }
+        }
+        ShapeKind::Rectangle => {
+            let corners = [from, (to.0, from.1), to, (from.0, to.1), from];
+            for pair in corners.windows(2) {
+                segment(target, pair[0], pair[1], color, width);
+            }
+        }
+        ShapeKind::Ellipse => {
+            let centre = ((from.0 + to.0) / 2.0, (from.1 + to.1) / 2.0);
+            let radius = ((to.0 - from.0).abs() / 2.0, (to.1 - from.1).abs() / 2.0);
+            let point = |step : usize| {
+                let angle = step as f32 / ELLIPSE_SEGMENTS as f32 * std::f32::consts::TAU;
+                (centre.0 + radius.0 * angle.cos(), centre.1 + radius.1 * angle.sin())
+            };
+            for step in 0..ELLIPSE_SEGMENTS {
+                segment(target, point(step), point(step + 1), color, width);
+            }
+        }
+        ShapeKind::Highlight => {
+            let (target_width, target_height) = target.size();
+            let left = from.0.min(to.0).round().max(0.0) as i32;
+            let top = from.1.min(to.1).round().max(0.0) as i32;
+            let right = (from.0.max(to.0).round() as i32).min(target_width as i32);
+            let bottom = (from.1.max(to.1).round() as i32).min(target_height as i32);
+            for y in top..bottom {
+                for x in left..right {
+                    target.blend(x, y, color, HIGHLIGHT_OPACITY);
+                }
+            }
+        }
+    }
+}
+
+// The ends of the two lines that make an arrow's head, none for an arrow with no length.
+pub fn arrow_head(from : (f32, f32), to : (f32, f32), width : f32) -> Vec<(f32, f32)> {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length = (dx * dx + dy * dy).sqrt();
+    if length == 0.0 {
+        return Vec::new();
+    }
+    let head = (width * ARROW_HEAD_SCALE).max(ARROW_HEAD_MIN);
+    let (back_x, back_y) = (-dx / length * head, -dy / length * head);
+    [ARROW_HEAD_ANGLE, -ARROW_HEAD_ANGLE].into_iter().map(|angle| {
+        let (sin, cos) = angle.sin_cos();
+        (to.0 + back_x * cos - back_y * sin, to.1 + back_x * sin + back_y * cos)
+    }).collect()
+}
+
+pub fn callout_layout(callout : &Callout, (left, top) : (f32, f32)) -> CalloutLayout {
+    let size = (callout.size as f32).max(MIN_TEXT_SIZE);
+    let scaled = font().as_scaled(PxScale::from(size));
+    let padding = (size / 4.0).round();
+    let origin = (left + padding, top + padding + scaled.ascent());
+    // Lay the line out first, the plate needs to know how wide it is.
+    let mut glyphs = Vec::new();
+    let mut caret = origin.0;
+    let mut previous = None;
+    for character in callout.text.chars() {
+        let id = scaled.glyph_id(character);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        glyphs.push(id.with_scale_and_position(size, point(caret, origin.1)));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    let right = (caret + padding).ceil() as i32;
+    let bottom = (top + padding * 2.0 + scaled.height()).ceil() as i32;
+    CalloutLayout { glyphs, plate : [left.round() as i32, top.round() as i32, right, bottom], origin, size }
+}
+
+fn draw_callout(target : &mut dyn RenderTarget, callout : &Callout, position : (f32, f32)) {
+    if callout.text.is_empty() {
+        return;
+    }
+    let layout = callout_layout(callout, position);
+    let (width, height) = target.size();
+    let [left, top, right, bottom] = layout.plate;
+    for y in top.max(0)..bottom.min(height as i32) {
+        for x in left.max(0)..right.min(width as i32) {
+            target.blend(x, y, PLATE_COLOR, PLATE_OPACITY);
+        }
+    }
+    for glyph in layout.glyphs {
+        if let Some(outline) = font().outline_glyph(glyph) {
+            let bounds = outline.px_bounds();
+            // Long labels run off the edge, no point drawing what can't be seen.
+            if bounds.max.x < 0.0 || bounds.min.x > width as f32 || bounds.max.y < 0.0 || bounds.min.y > height as f32 {
+                continue;
+            }
+            outline.draw(|x, y, coverage| target.blend(bounds.min.x as i32 + x as i32, bounds.min.y as i32 + y as i32, callout.color, coverage));
+        }
+    }
+}
+
+fn font() -> &'static FontRef<'static> {
+    FONT.get_or_init(|| FontRef::try_from_slice(include_bytes!("DejaVuSans.ttf")).expect("Failed to load the callout font?? uh oh"))
+}
+
+// A line with round ends, edges are softened by how far each pixel's centre is from the line.
+fn segment(target : &mut dyn RenderTarget, from : (f32, f32), to : (f32, f32), color : [u8; 3], width : f32) {
+    let radius = width / 2.0;
+    let (target_width, target_height) = target.size();
+    let left = ((from.0.min(to.0) - radius).floor() as i32 - 1).max(0);
+    let top = ((from.1.min(to.1) - radius).floor() as i32 - 1).max(0);
+    let right = ((from.0.max(to.0) + radius).ceil() as i32 + 1).min(target_width as i32 - 1);
+    let bottom = ((from.1.max(to.1) + radius).ceil() as i32 + 1).min(target_height as i32 - 1);
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length = dx * dx + dy * dy;
+    for y in top..=bottom {
+        for x in left..=right {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let along = if length == 0.0 { 0.0 } else { (((px - from.0) * dx + (py - from.1) * dy) / length).clamp(0.0, 1.0) };
+            let (cx, cy) = (from.0 + dx * along - px, from.1 + dy * along - py);
+            let coverage = (radius + 0.5 - (cx * cx + cy * cy).sqrt()).clamp(0.0, 1.0);
+            target.blend(x, y, color, coverage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use winit::dpi::{PhysicalPosition, PhysicalSize};
+    use crate::overlay::Tutor;
+    use crate::shared::{Animation, Annotation, Callout, Frame, MousePosition, Shape, ShapeKind, ShapeUpdate, StrokeStyle, UserState};
+    use crate::smoothing::Smoothing;
+    use super::{compose, BufferTarget, RgbaTarget, Screen};
+
+    // Channels can be off by this much, float rounding differs a little between machines.
+    const TOLERANCE : u8 = 2;
+
+    fn screen(x : i32, y : i32, width : u32, height : u32) -> Screen {
+        Screen { origin : PhysicalPosition::new(x, y), size : PhysicalSize::new(width, height) }
+    }
+
+    fn tutor(state : UserState) -> Tutor {
+        let mut tutor = Tutor::new(Smoothing { latency : Duration::ZERO, max_extrapolation : Duration::ZERO });
+        tutor.state = state;
+        tutor
+    }
+
+    fn render(screen : &Screen, primary : &Screen, tutors : &[&Tutor], now : Instant) -> RgbaTarget {
+        let mut target = RgbaTarget::new(screen.size.width, screen.size.height);
+        compose(&mut target, screen, primary, tutors, now);
+        target
+    }
+
+    // Run with UPDATE_GOLDEN=1 to write new reference images after an intended change.
+    fn check_golden(name : &str, target : &RgbaTarget) {
+        let path = format!("{}/tests/golden/{}.png", env!("CARGO_MANIFEST_DIR"), name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            target.image.save(&path).unwrap();
+            return;
+        }
+        let expected = image::open(&path).unwrap_or_else(|err| panic!("Couldn't open {}, {}", path, err)).to_rgba8();
+        assert_eq!(expected.dimensions(), target.image.dimensions(), "{} changed size", name);
+        let worst = expected.pixels().zip(target.image.pixels())
+            .flat_map(|(expected, actual)| expected.0.into_iter().zip(actual.0).map(|(a, b)| a.abs_diff(b)))
+            .max()
+            .unwrap_or(0);
+        assert!(worst <= TOLERANCE, "{} is off from its golden image by up to {} per channel", name, worst);
+    }
+
+    #[test]
+    fn visible_cursor() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 256, 256);
+        let mut tutor = tutor(UserState::Visible(MousePosition { x : 0.25, y : 0.5 }));
+        tutor.update(0.0, now);
+        assert_eq!(tutor.frame, 0);
+        check_golden("visible_cursor", &render(&primary, &primary, &[&tutor], now));
+    }
+
+    #[test]
+    fn flashing_picks_animation_frame() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 128, 128);
+        let mut tutor = tutor(UserState::Flashing(MousePosition { x : 0.25, y : 0.25 }));
+        tutor.set_image(image::load_from_memory(include_bytes!("gator_dragon_pointer.png")).unwrap());
+        tutor.animation_set.flashing = Animation {
+            frames : vec![Frame { index : 0, duration : 0.1 }, Frame { index : 1, duration : 0.1 }],
+        };
+        tutor.update(0.05, now);
+        assert_eq!(tutor.frame, 0);
+        tutor.update(0.15, now);
+        assert_eq!(tutor.frame, 1);
+        check_golden("flashing_frame", &render(&primary, &primary, &[&tutor], now));
+    }
+
+    #[test]
+    fn trail_fades_behind_cursor() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 256, 128);
+        let mut tutor = tutor(UserState::Visible(MousePosition { x : 0.75, y : 0.5 }));
+        tutor.update(0.0, now);
+        tutor.trail.clear();
+        for step in 1..=4u32 {
+            let age = Duration::from_millis(200 - step as u64 * 40);
+            tutor.trail.push_back((now - age, MousePosition { x : 0.15 * step as f32, y : 0.5 }));
+        }
+        check_golden("trail", &render(&primary, &primary, &[&tutor], now));
+    }
+
+    #[test]
+    fn cursor_spans_monitors() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 256, 256);
+        let secondary = screen(256, 0, 256, 256);
+        let mut left = tutor(UserState::Visible(MousePosition { x : 0.9, y : 0.1 }));
+        let mut right = tutor(UserState::Visible(MousePosition { x : 0.95, y : 0.15 }));
+        right.set_image(image::load_from_memory(include_bytes!("gator_dragon_pointer.png")).unwrap());
+        left.update(0.0, now);
+        right.update(0.0, now);
+        check_golden("span_primary", &render(&primary, &primary, &[&left, &right], now));
+        check_golden("span_secondary", &render(&secondary, &primary, &[&left, &right], now));
+    }
+
+    #[test]
+    fn ink_stays_under_cursor() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 256, 128);
+        let mut tutor = tutor(UserState::Visible(MousePosition { x : 0.5, y : 0.25 }));
+        tutor.ink.apply(Annotation::Begin {
+            stroke : 1,
+            style : StrokeStyle { color : [255, 48, 48], width : 6 },
+            point : MousePosition { x : 0.1, y : 0.8 },
+        });
+        tutor.ink.apply(Annotation::Extend {
+            stroke : 1,
+            points : vec![MousePosition { x : 0.5, y : 0.3 }, MousePosition { x : 0.9, y : 0.8 }],
+        });
+        // A dot on its own.
+        tutor.ink.apply(Annotation::Begin {
+            stroke : 2,
+            style : StrokeStyle { color : [48, 48, 255], width : 12 },
+            point : MousePosition { x : 0.1, y : 0.2 },
+        });
+        tutor.update(0.0, now);
+        check_golden("ink", &render(&primary, &primary, &[&tutor], now));
+    }
+
+    #[test]
+    fn shapes() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 256, 256);
+        let mut tutor = tutor(UserState::Idle);
+        let style = StrokeStyle { color : [48, 200, 48], width : 3 };
+        let shapes = [
+            (ShapeKind::Highlight, (0.05, 0.05), (0.45, 0.3)),
+            (ShapeKind::Rectangle, (0.55, 0.05), (0.95, 0.3)),
+            (ShapeKind::Ellipse, (0.05, 0.55), (0.45, 0.95)),
+            (ShapeKind::Arrow, (0.55, 0.95), (0.95, 0.55)),
+        ];
+        for (id, (kind, from, to)) in shapes.into_iter().enumerate() {
+            tutor.ink.shape(ShapeUpdate::Place(Shape {
+                id : id as u32,
+                kind,
+                style,
+                from : MousePosition { x : from.0, y : from.1 },
+                to : MousePosition { x : to.0, y : to.1 },
+            }));
+        }
+        check_golden("shapes", &render(&primary, &primary, &[&tutor], now));
+    }
+
+    #[test]
+    fn callout_on_plate() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 256, 96);
+        let mut tutor = tutor(UserState::Idle);
+        tutor.ink.callout(Callout {
+            id : 1,
+            position : MousePosition { x : 0.05, y : 0.1 },
+            text : "click here".to_string(),
+            size : 24,
+            color : [255, 220, 64],
+        });
+        tutor.ink.callout(Callout {
+            id : 2,
+            position : MousePosition { x : 0.5, y : 0.6 },
+            text : "typo!".to_string(),
+            size : 14,
+            color : [255, 96, 96],
+        });
+        check_golden("callouts", &render(&primary, &primary, &[&tutor], now));
+    }
+
+    #[test]
+    fn idle_draws_nothing() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 64, 64);
+        let mut tutor = tutor(UserState::Idle);
+        tutor.update(0.0, now);
+        let target = render(&primary, &primary, &[&tutor], now);
+        assert!(target.image.pixels().all(|pixel| pixel.0 == [0; 4]));
+    }
+
+    #[test]
+    fn window_buffer_is_xrgb() {
+        let now = Instant::now();
+        let primary = screen(0, 0, 64, 64);
+        let mut tutor = tutor(UserState::Idle);
+        tutor.ink.shape(ShapeUpdate::Place(Shape {
+            id : 1,
+            kind : ShapeKind::Rectangle,
+            style : StrokeStyle { color : [255, 0, 0], width : 4 },
+            from : MousePosition { x : 0.25, y : 0.25 },
+            to : MousePosition { x : 0.75, y : 0.75 },
+        }));
+        let mut pixels = vec![0; 64 * 64];
+        let mut target = BufferTarget { pixels : &mut pixels, width : 64, height : 64 };
+        compose(&mut target, &primary, &primary, &[&tutor], now);
+        // Red ends up in the third byte from the bottom, where softbuffer reads it.
+        let edge = pixels[16 * 64 + 32];
+        assert_eq!(edge & 0x00ff_ffff, 0x00ff_0000, "{:08x}", edge);
+        let expected = render(&primary, &primary, &[&tutor], now);
+        for (pixel, rgba) in pixels.iter().zip(expected.image.pixels()) {
+            let [r, g, b, a] = rgba.0;
+            assert_eq!(*pixel, u32::from_be_bytes([a, r, g, b]));
+        }
+    }
+}
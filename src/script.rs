@@ -0,0 +1,130 @@
+use std::io::BufRead;
+use std::sync::mpsc::{SendError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::client::PointerInput;
+use crate::shared::{MousePosition, UserState};
+
+// One command per line, blank lines and anything after a # are ignored.
+//   move <x> <y> [seconds]  positions go from 0 to 1 across the screen, glides there if given a time
+//   press                   show the pointer
+//   flash                   flash the pointer
+//   idle                    hide the pointer
+//   wait <seconds>
+//   cursor <path>           switch to another cursor sheet
+enum Command {
+    Move(MousePosition, Duration),
+    Press,
+    Flash,
+    Idle,
+    Wait(Duration),
+    Cursor(String),
+}
+
+// Blocks until the script runs out, or the connection does.
+pub fn run(script : impl BufRead, tx : &Sender<PointerInput>, sample_interval : Duration) {
+    let mut state = UserState::Idle;
+    let mut position = MousePosition { x : 0.5, y : 0.5 };
+    for (number, line) in script.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                println!("Stopped reading the script: {}", err);
+                return;
+            }
+        };
+        let command = match parse(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("Skipping line {} of the script, {}.", number + 1, err);
+                continue;
+            }
+        };
+        let sent = match command {
+            Command::Press => {
+                state = UserState::Visible(position);
+                tx.send(PointerInput::State(state.clone(), Instant::now()))
+            }
+            Command::Flash => {
+                state = UserState::Flashing(position);
+                tx.send(PointerInput::State(state.clone(), Instant::now()))
+            }
+            Command::Idle => {
+                state = UserState::Idle;
+                tx.send(PointerInput::State(state.clone(), Instant::now()))
+            }
+            Command::Wait(duration) => {
+                thread::sleep(duration);
+                Ok(())
+            }
+            Command::Cursor(path) => tx.send(PointerInput::Cursor(path)),
+            Command::Move(target, duration) => {
+                let result = glide(tx, &state, position, target, duration, sample_interval);
+                position = target;
+                state = state.with_position(target);
+                result
+            }
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+}
+
+fn glide(tx : &Sender<PointerInput>, state : &UserState, from : MousePosition, to : MousePosition, duration : Duration, sample_interval : Duration) -> Result<(), SendError<PointerInput>> {
+    let steps = (duration.as_secs_f32() / sample_interval.as_secs_f32()).ceil().max(1.0) as u32;
+    for step in 1..=steps {
+        if step > 1 {
+            thread::sleep(sample_interval);
+        }
+        let amount = step as f32 / steps as f32;
+        let position = MousePosition {
+            x : from.x + (to.x - from.x) * amount,
+            y : from.y + (to.y - from.y) * amount,
+        };
+        // Keep the timing even when hidden, so the rest of the script still lines up.
+        if *state != UserState::Idle {
+            tx.send(PointerInput::State(state.with_position(position), Instant::now()))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse(line : &str) -> Result<Option<Command>, String> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+    let arguments : Vec<&str> = words.collect();
+    let command = match (command, arguments.as_slice()) {
+        ("move", [x, y]) => Command::Move(MousePosition { x : coordinate(x)?, y : coordinate(y)? }, Duration::ZERO),
+        ("move", [x, y, time]) => Command::Move(MousePosition { x : coordinate(x)?, y : coordinate(y)? }, seconds(time)?),
+        ("press", []) => Command::Press,
+        ("flash", []) => Command::Flash,
+        ("idle", []) => Command::Idle,
+        ("wait", [time]) => Command::Wait(seconds(time)?),
+        // Paths can have spaces in them.
+        ("cursor", [_, ..]) => Command::Cursor(shellexpand::full(line["cursor".len()..].trim()).map_err(|err| err.to_string())?.to_string()),
+        ("move" | "press" | "flash" | "idle" | "wait" | "cursor", _) => return Err(format!("wrong arguments for {}", command)),
+        _ => return Err(format!("unknown command {}", command)),
+    };
+    Ok(Some(command))
+}
+
+fn coordinate(word : &str) -> Result<f32, String> {
+    match word.parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!("{} isn't a position between 0 and 1", word)),
+    }
+}
+
+fn seconds(word : &str) -> Result<Duration, String> {
+    // Anything too big for a Duration is as wrong as a negative one.
+    match word.parse::<f32>().ok().and_then(|value| Duration::try_from_secs_f32(value).ok()) {
+        Some(duration) => Ok(duration),
+        None => Err(format!("{} isn't a number of seconds", word)),
+    }
+}
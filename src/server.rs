@@ -1,127 +1,175 @@
 use std::error::Error;
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender, TryRecvError};
 use std::thread;
 use std::process::exit;
-use std::collections::HashMap;
-use winit::event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget};
+use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use winit::event_loop::ControlFlow;
 use winit::event::{Event, WindowEvent};
-use winit::dpi::{LogicalPosition, LogicalSize};
-use std::rc::Rc;
-use winit::window::{Window, WindowBuilder, WindowLevel};
-use std::num::NonZeroU32;
-use std::time::SystemTime;
-use image::{DynamicImage, GenericImageView};
-use winit::platform::windows::WindowBuilderExtWindows;
-use softbuffer::Surface;
-use steamworks::{Client, P2PSessionRequest, SteamId};
-use crate::{Config};
-use crate::shared::{CURSOR_SIZE, UserAnimationStates, UserState, UserPacket, APP_ID};
-use crate::shared::UserState::Idle;
+use std::time::{Duration, Instant, SystemTime};
+use image::DynamicImage;
+use sha2::{Digest, Sha256};
+use crate::{Config, RenderMode, platform, protocol};
+use crate::assets::{short_hash, AssetAssembly, CHUNK_SIZE};
+use crate::auth::{Authenticator, Throttle};
+use crate::cache::AssetCache;
+use crate::consent::Consent;
+use crate::export::export;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::shared::{CURSOR_SIZE, MAX_CURSOR_FRAMES, Annotation, Callout, ShapeUpdate, UserAnimationStates, UserPacket, Hello, HelloReply, APP_VERSION, AssetChunk, AssetHash, AssetKind, AssetManifest, AssetProgress, AssetStatus};
+use crate::transport::{PeerId, SendMode, Transport};
+use crate::overlay::{Compositor, Overlay, Tutor, TutorWindows};
+use crate::render::Screen;
+use crate::smoothing::{Sample, Smoothing};
+use crate::recording::{Recorder, Recording};
 
+// How many chunks go by between progress reports to the tutor.
+const PROGRESS_INTERVAL : usize = 16;
+// An upload that hasn't had a chunk in this long is dropped.
+const STALE_TRANSFER : Duration = Duration::from_secs(30);
+// What a tutor who disconnected had sent so far is kept this long, so they can pick up from there if they come back.
+const ORPHANED_TRANSFER : Duration = Duration::from_secs(5 * 60);
+// How many of the largest allowed uploads' worth of half-sent data we hold for tutors who might not come back.
+const ORPHANED_UPLOADS : usize = 4;
+
+#[derive(Clone)]
 enum UserData {
-    State(UserState),
+    State(Sample),
+    Left,
     AnimationStates(UserAnimationStates),
     Image(DynamicImage),
+    Annotation(Annotation),
+    Shape(ShapeUpdate),
+    Callout(Callout),
+    // Let in, with the key their layer is kept under so it's still there if they reconnect.
+    Joined { layer : String, name : String },
+    ClearAll,
+    // Save everyone's ink to this path, without an extension.
+    Export(String),
 }
 
 struct ThreadPacket {
-    owner : SteamId,
+    owner : PeerId,
     data : UserData,
 }
 
-struct UserWindow {
-    window : Rc<Window>,
-    surface : Surface<Rc<Window>, Rc<Window>>,
-    frame : u32,
-    state : UserState,
-    animation_set : UserAnimationStates,
-    image : DynamicImage
+struct ServerNetwork {
+    transport : Box<dyn Transport>,
+    tx : Sender<ThreadPacket>,
+    greeted : HashSet<PeerId>,
+    last_seen : HashMap<PeerId, Instant>,
+    last_sequence : HashMap<PeerId, u32>,
+    timeout : Duration,
+    transfers : HashMap<AssetHash, AssetAssembly>,
+    cache : AssetCache,
+    max_asset_size : usize,
+    authenticator : Authenticator,
+    throttle : Throttle,
+    consent : Consent,
+    recorder : Option<Recorder>,
+    // Each tutor's layer key and name, from their hello.
+    layers : HashMap<PeerId, (String, String)>,
+    export_dir : String,
+    // The one tutor allowed to clear everyone's ink, besides the student.
+    host : Option<String>,
 }
 
-pub fn server(_config: Config) -> Result<(), Box<dyn Error>> {
-    let (steam_client, single_client) = Client::init_app(APP_ID)?;
-
-    let steam_client_copy = steam_client.to_owned();
-    steam_client.register_callback(move |session_request : P2PSessionRequest| {
-        let networking = steam_client_copy.networking();
-        networking.accept_p2p_session(session_request.remote);
-    });
-
-    let steam_client_copy_also = steam_client.to_owned();
-    let steam_id = steam_client.user().steam_id().raw();
+pub fn server(config: Config, transport: Box<dyn Transport>) -> Result<(), Box<dyn Error>> {
     println!("Press CTRL+C to close.");
     println!("--");
-    println!("Please share this with your friend: {}", steam_id);
+    println!("Please share this with your friend: {}", transport.local_id());
 
     let (tx, rx): (Sender<ThreadPacket>, Receiver<ThreadPacket>) = channel();
+    let network = ServerNetwork::new(&config, transport, tx)?;
+    network.authenticator.announce();
+    println!("Type clear and press enter to wipe everything the tutors have drawn, or export to save it.");
     thread::spawn(move || {
-        loop {
-            let mut buf = [0;1000000];
-            match steam_client.networking().read_p2p_packet(&mut buf) {
-                None => {
-                    single_client.run_callbacks();
-                    let interval = std::time::Duration::from_millis(10);
-                    thread::sleep(interval);
+        network.run();
+    });
+    overlay(&config, rx)
+}
+
+// Plays a recording made with --record back on the overlay, as if the tutors in it were connected.
+pub fn replay(config: Config, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut recording = Recording::open(path)?;
+    println!("Replaying {}, press CTRL+C to close.", path);
+    let (tx, rx): (Sender<ThreadPacket>, Receiver<ThreadPacket>) = channel();
+    let path_name = path.to_string();
+    thread::spawn(move || {
+        let started = Instant::now();
+        let mut senders : HashMap<String, PeerId> = HashMap::new();
+        let mut transfers : HashMap<AssetHash, AssetAssembly> = HashMap::new();
+        while let Some(entry) = recording.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    println!("Stopped replaying, the recording is damaged: {}", err);
+                    break;
                 }
-                Some((steam_id, amt)) => {
-                    let buf = &mut buf[..amt];
-                    let deserialize: Result<UserPacket,_> = serde_json::from_slice(buf);
-                    match deserialize {
-                        Ok(packet) => {
-                            match packet {
-                                UserPacket::State(state) => {
-                                    match tx.send(ThreadPacket {
-                                        owner: steam_id,
-                                        data: UserData::State(state),
-                                    }) {
-                                        Ok(_) => {}
-                                        Err(_) => { break }
-                                    }
-                                }
-                                UserPacket::AnimationSet(set) => {
-                                    match tx.send(ThreadPacket {
-                                        owner: steam_id,
-                                        data: UserData::AnimationStates(set),
-                                    }) {
-                                        Ok(_) => {}
-                                        Err(_) => { break }
-                                    }
-                                }
+            };
+            let count = senders.len() as u64;
+            let owner = *senders.entry(entry.sender).or_insert(PeerId::Loopback(count));
+            let due = started + Duration::from_millis(entry.time as u64);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+            let data = match entry.packet {
+                UserPacket::State(update) => {
+                    let received = Instant::now();
+                    update.samples.into_iter().map(|sample| UserData::State(Sample {
+                        timestamp : sample.timestamp,
+                        received,
+                        state : sample.state,
+                    })).collect()
+                }
+                UserPacket::Goodbye => vec![UserData::Left],
+                packet @ (UserPacket::Annotation(_) | UserPacket::Shape(_) | UserPacket::Callout(_)) => ink_data(packet).into_iter().collect(),
+                UserPacket::AssetManifest(manifest) => {
+                    transfers.insert(manifest.hash, AssetAssembly::new(manifest));
+                    Vec::new()
+                }
+                UserPacket::AssetChunk(chunk) => {
+                    let complete = match transfers.get_mut(&chunk.hash) {
+                        Some(assembly) => assembly.push(&chunk).is_ok() && assembly.is_complete(),
+                        None => false,
+                    };
+                    if !complete {
+                        Vec::new()
+                    } else {
+                        let assembly = transfers.remove(&chunk.hash).unwrap();
+                        match assembly.verify().and_then(|_| finish_asset(&assembly.manifest, &assembly.data)) {
+                            Ok(data) => vec![data],
+                            Err(err) => {
+                                println!("Skipping a {} in the recording, {}.", assembly.manifest.kind.describe(), err);
+                                Vec::new()
                             }
                         }
-                        Err(err) => {
-                            println!("{}", err);
-                            match image::load_from_memory(buf) {
-                                Ok(image) => {
-                                    if image.width()%CURSOR_SIZE != 0 {
-                                        println!("Failed to use image, its width isn't a factor of {}!", CURSOR_SIZE);
-                                    } else if image.height() != CURSOR_SIZE {
-                                        println!("Failed to use image, its height isn't {}!", CURSOR_SIZE);
-                                    } else {
-                                        match tx.send(ThreadPacket {
-                                            owner: steam_id,
-                                            data: UserData::Image(image),
-                                        }) {
-                                            Ok(_) => {}
-                                            Err(_) => {break}
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    println!("Failed to load image from user, corruption? {}", err);
-                                }
-                            };
-                        }
-                    };
+                    }
+                }
+                _ => Vec::new(),
+            };
+            for data in data {
+                if tx.send(ThreadPacket { owner, data }).is_err() {
+                    return;
                 }
             }
         }
+        println!("Finished replaying {}.", path_name);
     });
+    overlay(&config, rx)
+}
 
+fn overlay(config: &Config, rx: Receiver<ThreadPacket>) -> Result<(), Box<dyn Error>> {
+    let smoothing = Smoothing {
+        latency : Duration::from_millis(config.smoothing_latency),
+        max_extrapolation : Duration::from_millis(config.extrapolation),
+    };
     let now = SystemTime::now();
-    let mut user_windows = HashMap::new();
-    let event_loop = EventLoop::new().expect("Failed to build event loop");
+    let mut tutors : HashMap<PeerId, Tutor> = HashMap::new();
+    let event_loop = platform::overlay_event_loop();
     event_loop.set_control_flow(ControlFlow::Poll);
+    let mut display : Box<dyn Overlay> = match config.render_mode {
+        RenderMode::Windows => Box::new(TutorWindows::new()),
+        RenderMode::Overlay => Box::new(Compositor::new(&event_loop, true)),
+    };
     event_loop.run(move |event, elwt| {
         match event {
             Event::WindowEvent {
@@ -132,68 +180,107 @@ pub fn server(_config: Config) -> Result<(), Box<dyn Error>> {
                 exit(0);
             },
             Event::AboutToWait => {
-                update_windows(&now, &mut user_windows);
-                let user_packet = match rx.try_recv() {
-                    Ok(packet) => packet,
-                    Err(TryRecvError::Empty) => {
-                        let interval = std::time::Duration::from_millis(10);
-                        thread::sleep(interval);
-                        return;
-                    }
-                    Err(TryRecvError::Disconnected) => {
-                        println!("Lost connection with server thread.");
-                        exit(1);
-                    }
-                };
-                if !user_windows.contains_key(&user_packet.owner) {
-                    let friend_name = steam_client_copy_also.friends().get_friend(user_packet.owner).name();
-                    println!("Got a connection from {}", friend_name);
-                    user_windows.insert(user_packet.owner.clone(), create_server_window(&elwt));
-                }
-                let user_info = user_windows.get_mut(&user_packet.owner).unwrap();
-                let window = &user_info.window;
-                match user_packet.data {
-                    UserData::State(state) => {
-                        user_info.state = state.clone();
-                        let monitor_size = window.primary_monitor().expect("Failed to detect primary monitor.").size();
-                        match state {
-                            UserState::Idle => {
-                                window.set_outer_position(LogicalPosition::new(-1000, -1000));
-                            }
-                            UserState::Visible(position) => {
-                                window.set_outer_position(LogicalPosition::new(position.x * monitor_size.width as f32, position.y * monitor_size.height as f32));
-                                let frame = user_info.animation_set.visible.get_frame(now.elapsed().unwrap().as_secs_f32()).index;
-                                if user_info.frame != frame {
-                                    set_frame(&mut user_info.surface, &user_info.image, frame);
-                                    user_info.frame = frame;
+                // Everything that arrived since the last frame goes in before drawing, or a busy tutor would fall further and further behind.
+                let mut received = false;
+                loop {
+                    let user_packet = match rx.try_recv() {
+                        Ok(packet) => packet,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            println!("Lost connection with server thread.");
+                            exit(1);
+                        }
+                    };
+                    received = true;
+                    match user_packet.data {
+                        UserData::Left => {
+                            // What they drew stays up in case they come back.
+                            match tutors.get_mut(&user_packet.owner) {
+                                Some(tutor) if tutor.ink.has_history() => tutor.leave(smoothing),
+                                _ => {
+                                    tutors.remove(&user_packet.owner);
                                 }
                             }
-                            UserState::Flashing(position) => {
-                                window.set_outer_position(LogicalPosition::new(position.x * monitor_size.width as f32, position.y * monitor_size.height as f32));
-                                let frame = user_info.animation_set.flashing.get_frame(now.elapsed().unwrap().as_secs_f32()).index;
-                                if user_info.frame != frame {
-                                    set_frame(&mut user_info.surface, &user_info.image, frame);
-                                    user_info.frame = frame;
+                            continue;
+                        }
+                        UserData::Joined { layer, name } => {
+                            let returning = match tutors.contains_key(&user_packet.owner) {
+                                true => Some(user_packet.owner),
+                                // Whoever had this layer is them from before, even if we haven't noticed that connection drop yet.
+                                false => tutors.iter().find(|(_, tutor)| tutor.layer == layer).map(|(peer, _)| *peer),
+                            };
+                            let mut tutor = match returning.and_then(|peer| tutors.remove(&peer)) {
+                                Some(tutor) => tutor,
+                                None => Tutor::new(smoothing),
+                            };
+                            tutor.layer = layer;
+                            tutor.name = name;
+                            tutor.connected = true;
+                            tutors.insert(user_packet.owner, tutor);
+                            continue;
+                        }
+                        UserData::ClearAll => {
+                            tutors.retain(|_, tutor| tutor.connected);
+                            for tutor in tutors.values_mut() {
+                                tutor.ink.apply(Annotation::ClearAll);
+                            }
+                            continue;
+                        }
+                        UserData::Export(base) => {
+                            // Tutors point at the primary monitor, so that's the size the drawing is saved at.
+                            let primary = match elwt.primary_monitor().or_else(|| elwt.available_monitors().next()) {
+                                Some(monitor) => Screen { origin : monitor.position(), size : monitor.size() },
+                                None => {
+                                    println!("Couldn't find a monitor to export at the size of.");
+                                    continue;
                                 }
+                            };
+                            let mut ordered : Vec<(&PeerId, &Tutor)> = tutors.iter().filter(|(_, tutor)| !tutor.ink.marks.is_empty()).collect();
+                            ordered.sort_by_key(|(peer, _)| peer.to_string());
+                            let ordered : Vec<&Tutor> = ordered.into_iter().map(|(_, tutor)| tutor).collect();
+                            match export(&base, &primary, &ordered) {
+                                Ok(()) => println!("Saved what {} tutors drew to {}.png and {}.svg.", ordered.len(), base, base),
+                                Err(err) => println!("Failed to export to {}: {}", base, err),
                             }
+                            continue;
                         }
+                        _ => {}
                     }
-                    UserData::Image(image) => {
-                        if image.width()%CURSOR_SIZE != 0 {
-                            println!("Failed to use image, its width isn't a factor of {}!", CURSOR_SIZE);
-                        } else if image.height() != CURSOR_SIZE {
-                            println!("Failed to use image, its height isn't {}!", CURSOR_SIZE);
-                        } else {
-                            user_info.image = image;
-                            set_frame(&mut user_info.surface, &user_info.image, 0);
+                    let tutor = tutors.entry(user_packet.owner).or_insert_with(|| Tutor::new(smoothing));
+                    tutor.connected = true;
+                    match user_packet.data {
+                        UserData::State(sample) => {
+                            tutor.motion.push(sample);
                         }
+                        UserData::Image(image) => {
+                            if image.width()%CURSOR_SIZE != 0 {
+                                println!("Failed to use image, its width isn't a factor of {}!", CURSOR_SIZE);
+                            } else if image.height() != CURSOR_SIZE {
+                                println!("Failed to use image, its height isn't {}!", CURSOR_SIZE);
+                            } else {
+                                tutor.set_image(image);
+                            }
+                        }
+                        UserData::AnimationStates(new_animation_set) => {
+                            println!("Received custom animation set.");
+                            tutor.animation_set = new_animation_set;
+                        }
+                        UserData::Annotation(annotation) => tutor.ink.apply(annotation),
+                        UserData::Shape(update) => tutor.ink.shape(update),
+                        UserData::Callout(callout) => tutor.ink.callout(callout),
+                        UserData::Left | UserData::Joined { .. } | UserData::ClearAll | UserData::Export(_) => {}
                     }
-                    UserData::AnimationStates(new_animation_set) => {
-                        println!("Received custom animation set.");
-                        user_info.animation_set = new_animation_set;
-                    }
                 }
-                window.request_redraw();
+                let elapsed = now.elapsed().unwrap().as_secs_f32();
+                let instant = Instant::now();
+                for tutor in tutors.values_mut() {
+                    tutor.update(elapsed, instant);
+                }
+                display.draw(elwt, &tutors);
+                if !received {
+                    let interval = std::time::Duration::from_millis(10);
+                    thread::sleep(interval);
+                }
             },
             _ => ()
         }
@@ -201,71 +288,561 @@ pub fn server(_config: Config) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn update_windows(now : &SystemTime, windows : &mut HashMap<SteamId,UserWindow>) {
-    for (_steamid, user_info) in windows {
-        match &user_info.state {
-            UserState::Visible(_) => {
-                let frame = user_info.animation_set.visible.get_frame(now.elapsed().unwrap().as_secs_f32()).index;
-                if user_info.frame != frame {
-                    set_frame(&mut user_info.surface, &user_info.image, frame);
-                    user_info.frame = frame;
+impl ServerNetwork {
+    fn new(config : &Config, transport : Box<dyn Transport>, tx : Sender<ThreadPacket>) -> Result<ServerNetwork, Box<dyn Error>> {
+        Ok(ServerNetwork {
+            transport,
+            tx,
+            greeted : HashSet::new(),
+            last_seen : HashMap::new(),
+            last_sequence : HashMap::new(),
+            timeout : Duration::from_secs_f32(config.timeout),
+            transfers : HashMap::new(),
+            cache : AssetCache::new(&config.cache_dir),
+            max_asset_size : config.max_cursor_size,
+            authenticator : Authenticator::new(&config.require_pin, config.one_time_token),
+            throttle : Throttle::default(),
+            consent : Consent::new(&config.tutor_list, !config.auto_accept),
+            recorder : match &config.record {
+                Some(path) => {
+                    println!("Recording the session to {}.", path);
+                    Some(Recorder::create(path)?)
+                }
+                None => None,
+            },
+            layers : HashMap::new(),
+            host : config.host.clone(),
+            export_dir : config.export_dir.clone(),
+        })
+    }
+
+    fn run(mut self) {
+        loop {
+            match self.poll() {
+                Ok(true) => {}
+                Ok(false) => {
+                    let interval = std::time::Duration::from_millis(10);
+                    thread::sleep(interval);
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    // One trip round the network loop, false when there was nothing to receive.
+    fn poll(&mut self) -> Result<bool, SendError<ThreadPacket>> {
+        while let Some(peer) = self.transport.accept() {
+            println!("{} is connecting...", self.transport.peer_name(peer));
+        }
+        self.handle_consent();
+        self.handle_commands()?;
+        while let Some(peer) = self.transport.closed() {
+            if self.greeted.contains(&peer) || self.layers.contains_key(&peer) {
+                self.drop_peer(peer, "disconnected")?;
+            }
+        }
+        self.drop_silent_peers()?;
+        self.expire_transfers();
+        let (peer, buf) = match self.transport.receive() {
+            Some(received) => received,
+            None => return Ok(false),
+        };
+        match protocol::decode(&buf) {
+            Ok(packet) => self.handle_packet(peer, packet)?,
+            Err(err) => println!("Ignoring packet from {}: {}", self.transport.peer_name(peer), err),
+        }
+        Ok(true)
+    }
+
+    fn handle_packet(&mut self, peer : PeerId, packet : UserPacket) -> Result<(), SendError<ThreadPacket>> {
+        if let UserPacket::Hello(hello) = packet {
+            self.handle_hello(peer, hello);
+            return Ok(());
+        }
+        if !self.greeted.contains(&peer) {
+            println!("Ignoring packet from {}, they haven't said hello.", self.transport.peer_name(peer));
+            return Ok(());
+        }
+        self.last_seen.insert(peer, Instant::now());
+        match packet {
+            UserPacket::Heartbeat => {}
+            UserPacket::Goodbye => self.drop_peer(peer, "left")?,
+            UserPacket::State(update) => {
+                if let Some(last_sequence) = self.last_sequence.get(&peer) {
+                    if !update.is_newer_than(*last_sequence) {
+                        return Ok(());
+                    }
+                }
+                self.last_sequence.insert(peer, update.sequence);
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.packet(peer, &UserPacket::State(update.clone()));
+                }
+                let received = Instant::now();
+                for sample in update.samples {
+                    self.tx.send(ThreadPacket {
+                        owner: peer,
+                        data: UserData::State(Sample {
+                            timestamp : sample.timestamp,
+                            received,
+                            state : sample.state,
+                        }),
+                    })?;
+                }
+            }
+            UserPacket::Annotation(Annotation::ClearAll) if !self.is_host(peer) => {
+                println!("Ignored {} asking to clear everyone's ink, only the host can do that.", self.transport.peer_name(peer));
+            }
+            UserPacket::Annotation(_) | UserPacket::Shape(_) | UserPacket::Callout(_) => {
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.packet(peer, &packet);
+                }
+                if let Some(data) = ink_data(packet) {
+                    self.tx.send(ThreadPacket {
+                        owner: peer,
+                        data,
+                    })?;
+                }
+            }
+            UserPacket::AssetManifest(manifest) => self.handle_manifest(peer, manifest)?,
+            UserPacket::AssetChunk(chunk) => self.handle_chunk(chunk)?,
+            UserPacket::Hello(_) | UserPacket::HelloReply(_) | UserPacket::AssetStatus(_) => {}
+        }
+        Ok(())
+    }
+
+    fn handle_commands(&mut self) -> Result<(), SendError<ThreadPacket>> {
+        while let Some(command) = self.consent.command() {
+            let command = command.trim();
+            // Only the command itself ignores case, paths on some systems don't.
+            let (verb, argument) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+            match (verb.to_lowercase().as_str(), argument.trim()) {
+                ("", _) => {}
+                ("clear", "") => {
+                    println!("Cleared everything the tutors drew.");
+                    let owner = self.transport.local_id();
+                    if let Some(recorder) = &mut self.recorder {
+                        recorder.packet(owner, &UserPacket::Annotation(Annotation::ClearAll));
+                    }
+                    self.tx.send(ThreadPacket {
+                        owner,
+                        data: UserData::ClearAll,
+                    })?;
+                }
+                ("export", path) => {
+                    let base = match path {
+                        "" => {
+                            let stamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+                            format!("{}/annotations-{}", self.export_dir, stamp)
+                        }
+                        path => shellexpand::full(path).map(|path| path.to_string()).unwrap_or(path.to_string()),
+                    };
+                    let base = base.trim_end_matches(".png").trim_end_matches(".svg").to_string();
+                    self.tx.send(ThreadPacket {
+                        owner: self.transport.local_id(),
+                        data: UserData::Export(base),
+                    })?;
+                }
+                _ => println!("Unknown command {}, try clear or export.", command),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_host(&self, peer : PeerId) -> bool {
+        self.host.is_some() && peer.stable_key() == self.host
+    }
+
+    fn drop_silent_peers(&mut self) -> Result<(), SendError<ThreadPacket>> {
+        let silent : Vec<PeerId> = self.last_seen.iter()
+            .filter(|(_, last_seen)| last_seen.elapsed() > self.timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in silent {
+            self.drop_peer(peer, "timed out")?;
+        }
+        Ok(())
+    }
+
+    fn expire_transfers(&mut self) {
+        let stale : Vec<AssetHash> = self.transfers.iter()
+            .filter(|(_, assembly)| assembly.updated.elapsed() > if assembly.peers.is_empty() { ORPHANED_TRANSFER } else { STALE_TRANSFER })
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in stale {
+            let assembly = self.transfers.remove(&hash).unwrap();
+            println!("Gave up on {} {}, nothing arrived for a while.", assembly.manifest.kind.describe(), short_hash(&hash));
+            for peer in assembly.peers {
+                self.send_status(peer, hash, assembly.data.len() as u32, AssetProgress::Failed("The upload stalled.".to_string()));
+            }
+        }
+    }
+
+    // Stops waiting on the peer for the matching uploads, any nobody else is sending are thrown away.
+    fn abandon_transfers(&mut self, peer : PeerId, which : impl Fn(&AssetAssembly) -> bool) {
+        self.transfers.retain(|_, assembly| {
+            if !which(assembly) || !assembly.peers.remove(&peer) {
+                return true;
+            }
+            !assembly.peers.is_empty()
+        });
+    }
+
+    // Keeps what the peer had sent under its hash, offering the same asset again carries on from there.
+    fn orphan_transfers(&mut self, peer : PeerId) {
+        for assembly in self.transfers.values_mut() {
+            assembly.peers.remove(&peer);
+        }
+        let mut orphaned : Vec<(Instant, AssetHash, usize)> = self.transfers.iter()
+            .filter(|(_, assembly)| assembly.peers.is_empty())
+            .map(|(hash, assembly)| (assembly.updated, *hash, assembly.data.len()))
+            .collect();
+        // Newest first, the oldest go once there's too much.
+        orphaned.sort_by_key(|(updated, _, _)| Reverse(*updated));
+        let mut kept = 0;
+        for (_, hash, size) in orphaned {
+            kept += size;
+            if kept > ORPHANED_UPLOADS * self.max_asset_size {
+                self.transfers.remove(&hash);
+            }
+        }
+    }
+
+    fn drop_peer(&mut self, peer : PeerId, reason : &str) -> Result<(), SendError<ThreadPacket>> {
+        println!("Tutor {} {}.", self.transport.peer_name(peer), reason);
+        self.greeted.remove(&peer);
+        self.last_seen.remove(&peer);
+        self.last_sequence.remove(&peer);
+        self.layers.remove(&peer);
+        self.orphan_transfers(peer);
+        self.transport.disconnect(peer);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.packet(peer, &UserPacket::Goodbye);
+        }
+        self.tx.send(ThreadPacket {
+            owner: peer,
+            data: UserData::Left,
+        })
+    }
+
+    fn handle_hello(&mut self, peer : PeerId, hello : Hello) {
+        let peer_name = self.transport.peer_name(peer);
+        if hello.protocol_version != PROTOCOL_VERSION {
+            println!("Rejected {} ({}), they're running laser_pointer {} with protocol {} but we're running {} with protocol {}.",
+                     hello.name, peer_name, hello.app_version, hello.protocol_version, APP_VERSION, PROTOCOL_VERSION);
+            self.answer_hello(peer, HelloReply::reject(format!("The student is running laser_pointer {} with protocol {}, you need a build with the same protocol.", APP_VERSION, PROTOCOL_VERSION)));
+            return;
+        }
+        let layer = layer_key(peer, &hello.install);
+        self.layers.insert(peer, (layer, hello.name.clone()));
+        let known = self.consent.lookup(peer);
+        if known == Some(false) {
+            println!("Turned away {} ({}), they're on your never list.", hello.name, peer_name);
+            self.answer_hello(peer, HelloReply::reject("The student declined the connection.".to_string()));
+            return;
+        }
+        if self.throttle.is_locked(peer) {
+            println!("Rejected {} ({}), too many wrong PINs or tokens from there lately.", hello.name, peer_name);
+            self.answer_hello(peer, HelloReply::reject("Too many wrong PINs or tokens, wait a minute before trying again.".to_string()));
+            return;
+        }
+        if !self.authenticator.check(&hello.secret) {
+            self.throttle.failed(peer);
+            println!("Rejected {} ({}), they didn't have the right PIN or token.", hello.name, peer_name);
+            self.answer_hello(peer, HelloReply::reject("The student asked for a PIN or token, and yours didn't match.".to_string()));
+            return;
+        }
+        self.throttle.succeeded(peer);
+        if known == Some(true) {
+            println!("Got a connection from {} ({}), running laser_pointer {}", hello.name, peer_name, hello.app_version);
+            self.answer_hello(peer, HelloReply::accept());
+            return;
+        }
+        let description = format!("{} ({}), running laser_pointer {},", hello.name, peer_name, hello.app_version);
+        self.consent.ask(peer, &hello.name, description);
+    }
+
+    fn handle_consent(&mut self) {
+        while let Some((peer, allowed)) = self.consent.poll() {
+            // They were dropped while the student was deciding, there's nobody to answer.
+            if !self.layers.contains_key(&peer) {
+                println!("{} left before you answered.", self.transport.peer_name(peer));
+                continue;
+            }
+            if allowed {
+                println!("Let {} in.", self.transport.peer_name(peer));
+                self.answer_hello(peer, HelloReply::accept());
+            } else {
+                println!("Turned {} away.", self.transport.peer_name(peer));
+                self.answer_hello(peer, HelloReply::reject("The student declined the connection.".to_string()));
+            }
+        }
+    }
+
+    fn answer_hello(&mut self, peer : PeerId, reply : HelloReply) {
+        let accepted = reply.accepted;
+        self.send(peer, UserPacket::HelloReply(reply));
+        if accepted {
+            self.greeted.insert(peer);
+            self.last_seen.insert(peer, Instant::now());
+            self.last_sequence.remove(&peer);
+            let (layer, name) = self.layers.get(&peer).cloned().unwrap_or_else(|| (peer.to_string(), self.transport.peer_name(peer)));
+            let _ = self.tx.send(ThreadPacket {
+                owner: peer,
+                data: UserData::Joined { layer, name },
+            });
+        } else {
+            self.greeted.remove(&peer);
+            self.layers.remove(&peer);
+            self.transport.disconnect(peer);
+        }
+    }
+
+    fn handle_manifest(&mut self, peer : PeerId, manifest : AssetManifest) -> Result<(), SendError<ThreadPacket>> {
+        let hash = manifest.hash;
+        let name = manifest.kind.describe();
+        if manifest.total_size as usize > self.max_asset_size {
+            println!("Refusing a {} byte {} from {}, the limit is {} bytes.", manifest.total_size, name, self.transport.peer_name(peer), self.max_asset_size);
+            let reason = format!("The {} is {} bytes, the student only accepts up to {} bytes.", name, manifest.total_size, self.max_asset_size);
+            self.send_status(peer, hash, 0, AssetProgress::Failed(reason));
+            return Ok(());
+        }
+        if manifest.kind == AssetKind::CursorSheet && manifest.frame_count == 0 {
+            self.send_status(peer, hash, 0, AssetProgress::Failed("The cursor has no frames.".to_string()));
+            return Ok(());
+        }
+        if let Some(bytes) = self.cache.get(&hash) {
+            println!("Using cached {} {} for {}.", name, short_hash(&hash), self.transport.peer_name(peer));
+            let progress = match finish_asset(&manifest, &bytes) {
+                Err(err) => AssetProgress::Failed(format!("The {} was rejected, {}.", name, err)),
+                Ok(data) => {
+                    if let Some(recorder) = &mut self.recorder {
+                        recorder.asset(peer, &manifest, &bytes);
+                    }
+                    self.tx.send(ThreadPacket {
+                        owner: peer,
+                        data,
+                    })?;
+                    AssetProgress::Complete
+                }
+            };
+            self.send_status(peer, hash, manifest.total_size, progress);
+            return Ok(());
+        }
+        // A new cursor or animation set replaces whatever of that kind they were still sending, so each tutor has at most one upload per kind going.
+        let kind = manifest.kind;
+        self.abandon_transfers(peer, |assembly| assembly.manifest.kind == kind && assembly.manifest.hash != hash);
+        // A transfer with the same hash is either someone else's identical asset or an upload that got cut off, either way we keep what we have.
+        let assembly = self.transfers.entry(hash).or_insert_with(|| AssetAssembly::new(manifest));
+        assembly.peers.insert(peer);
+        let received = assembly.received();
+        println!("Receiving {} {} from {}, {} of {} bytes already here.", name, short_hash(&hash), self.transport.peer_name(peer), received, assembly.manifest.total_size);
+        self.send_status(peer, hash, received, AssetProgress::Receiving);
+        Ok(())
+    }
+
+    fn handle_chunk(&mut self, chunk : AssetChunk) -> Result<(), SendError<ThreadPacket>> {
+        let hash = chunk.hash;
+        let assembly = match self.transfers.get_mut(&hash) {
+            Some(assembly) => assembly,
+            None => return Ok(()),
+        };
+        let name = assembly.manifest.kind.describe();
+        let result = assembly.push(&chunk);
+        let received = assembly.received();
+        let peers : Vec<PeerId> = assembly.peers.iter().copied().collect();
+        let progress = match result {
+            Err(err) => {
+                self.transfers.remove(&hash);
+                AssetProgress::Failed(format!("The {} was rejected, {}.", name, err))
+            }
+            // Out of order, let them know where to carry on from.
+            Ok(false) => AssetProgress::Receiving,
+            Ok(true) if !assembly.is_complete() => {
+                if !(received as usize / CHUNK_SIZE).is_multiple_of(PROGRESS_INTERVAL) {
+                    return Ok(());
                 }
+                AssetProgress::Receiving
             }
-            UserState::Flashing(_) => {
-                let frame = user_info.animation_set.flashing.get_frame(now.elapsed().unwrap().as_secs_f32()).index;
-                if user_info.frame != frame {
-                    set_frame(&mut user_info.surface, &user_info.image, frame);
-                    user_info.frame = frame;
+            Ok(true) => {
+                let assembly = self.transfers.remove(&hash).unwrap();
+                match assembly.verify().and_then(|_| finish_asset(&assembly.manifest, &assembly.data)) {
+                    Err(err) => AssetProgress::Failed(format!("The {} was rejected, {}.", name, err)),
+                    Ok(data) => {
+                        self.cache.put(&hash, &assembly.data);
+                        for peer in &peers {
+                            if let Some(recorder) = &mut self.recorder {
+                                recorder.asset(*peer, &assembly.manifest, &assembly.data);
+                            }
+                            self.tx.send(ThreadPacket {
+                                owner: *peer,
+                                data: data.clone(),
+                            })?;
+                        }
+                        AssetProgress::Complete
+                    }
                 }
             }
-            _ => {}
+        };
+        if let AssetProgress::Failed(reason) = &progress {
+            println!("Failed to receive {} {}: {}", name, short_hash(&hash), reason);
+        }
+        for peer in peers {
+            self.send_status(peer, hash, received, progress.clone());
         }
+        Ok(())
+    }
+
+    fn send_status(&mut self, peer : PeerId, hash : AssetHash, received : u32, progress : AssetProgress) {
+        self.send(peer, UserPacket::AssetStatus(AssetStatus { hash, received, progress }));
+    }
+
+    fn send(&mut self, peer : PeerId, packet : UserPacket) {
+        self.transport.send(peer, SendMode::Reliable, &protocol::encode(&packet));
+    }
+}
+
+// What a tutor's ink is kept under between connections. Their install id is paired with where they connect from so it can't
+// just be copied, and hashed so exports don't give it away.
+fn layer_key(peer : PeerId, install : &str) -> String {
+    if install.is_empty() {
+        // Older builds only get their ink back when the transport knows who they are.
+        return peer.stable_key().unwrap_or_else(|| peer.to_string());
+    }
+    let source = peer.stable_key().unwrap_or_else(|| "relay".to_string());
+    format!("{}#{}", source, hex::encode(&Sha256::digest(install.as_bytes())[..8]))
+}
+
+fn ink_data(packet : UserPacket) -> Option<UserData> {
+    match packet {
+        UserPacket::Annotation(Annotation::ClearAll) => Some(UserData::ClearAll),
+        UserPacket::Annotation(annotation) => Some(UserData::Annotation(annotation)),
+        UserPacket::Shape(update) => Some(UserData::Shape(update)),
+        UserPacket::Callout(callout) => Some(UserData::Callout(callout)),
+        _ => None,
+    }
+}
+
+fn finish_asset(manifest : &AssetManifest, bytes : &[u8]) -> Result<UserData, String> {
+    match manifest.kind {
+        AssetKind::CursorSheet => Ok(UserData::Image(finish_cursor(manifest, bytes)?)),
+        AssetKind::AnimationSet => match protocol::decode_animation_set(bytes) {
+            Ok(set) => Ok(UserData::AnimationStates(set)),
+            Err(err) => Err(format!("it couldn't be read, {}", err)),
+        },
     }
 }
 
-fn set_frame(surface : &mut Surface<Rc<Window>,Rc<Window>>, image : &DynamicImage, frame : u32) {
-    if image.width()-frame*CURSOR_SIZE <= 0 {
-        return;
+fn finish_cursor(manifest : &AssetManifest, bytes : &[u8]) -> Result<DynamicImage, String> {
+    let image = match image::load_from_memory(bytes) {
+        Ok(image) => image,
+        Err(err) => return Err(format!("it isn't an image we can read, {}", err)),
+    };
+    if image.width()%CURSOR_SIZE != 0 {
+        return Err(format!("its width isn't a factor of {}", CURSOR_SIZE));
     }
-    let mut buffer = surface.buffer_mut().unwrap();
-    let image_crop = image.crop_imm(frame*CURSOR_SIZE,0,CURSOR_SIZE,CURSOR_SIZE);
-    for index in 0..(CURSOR_SIZE * CURSOR_SIZE) {
-        let y = index / CURSOR_SIZE;
-        let x = index % CURSOR_SIZE;
-        buffer[index as usize] = u32::from_ne_bytes(image_crop.get_pixel(x,y).0);
+    if image.height() != CURSOR_SIZE {
+        return Err(format!("its height isn't {}", CURSOR_SIZE));
     }
-    buffer.present().unwrap();
+    if image.width()/CURSOR_SIZE > MAX_CURSOR_FRAMES {
+        return Err(format!("it has more than {} frames", MAX_CURSOR_FRAMES));
+    }
+    if image.width()/CURSOR_SIZE != manifest.frame_count {
+        return Err(format!("it has {} frames but the manifest said {}", image.width()/CURSOR_SIZE, manifest.frame_count));
+    }
+    Ok(image)
 }
 
-fn create_server_window(event_loop : &EventLoopWindowTarget<()>) -> UserWindow {
-    let window = Rc::new(WindowBuilder::new().with_title("Laser Pointer")
-        .with_decorations(false)
-        .with_inner_size(LogicalSize::new(64, 64))
-        .with_resizable(false)
-        .with_skip_taskbar(true)
-        .with_window_level(WindowLevel::AlwaysOnTop)
-        .with_transparent(true)
-        .build(&event_loop).expect("Failed to build window"));
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use clap::Parser;
+    use crate::Config;
+    use crate::protocol::{decode, encode};
+    use crate::shared::{AssetKind, Hello, MousePosition, StateSample, StateUpdate, UserPacket, UserState};
+    use crate::assets::{chunks_from, manifest_for, CHUNK_SIZE};
+    use crate::transport::{LoopbackNetwork, PeerId, SendMode, Transport};
+    use super::{ServerNetwork, UserData};
+
+    // Plenty for anything sent in-process to come through, without hanging a broken test forever.
+    const POLLS : usize = 100;
+
+    fn config(extra : &[&str]) -> Config {
+        let scratch = std::env::temp_dir().join(format!("laser_pointer-test-{}", std::process::id()));
+        let mut args = vec!["laser_pointer".to_string(), "--auto-accept".to_string()];
+        args.push(format!("--cache-dir={}", scratch.join("cache").display()));
+        args.push(format!("--tutor-list={}", scratch.join("tutors.json").display()));
+        args.extend(extra.iter().map(|arg| arg.to_string()));
+        Config::parse_from(args)
+    }
+
+    // Sends the packet and polls until the tutor hears something back.
+    fn exchange(server : &mut ServerNetwork, tutor : &mut dyn Transport, student_id : PeerId, packet : &UserPacket) -> Option<UserPacket> {
+        tutor.send(student_id, SendMode::Reliable, &encode(packet));
+        for _ in 0..POLLS {
+            server.poll().unwrap();
+            if let Some((_, buf)) = tutor.receive() {
+                return Some(decode(&buf).unwrap());
+            }
+        }
+        None
+    }
+
+    fn greet(server : &mut ServerNetwork, tutor : &mut dyn Transport, student_id : PeerId) {
+        tutor.connect(student_id).unwrap();
+        let reply = exchange(server, tutor, student_id, &UserPacket::Hello(Hello::new("tester".to_string(), String::new(), String::new())));
+        assert!(matches!(reply, Some(UserPacket::HelloReply(reply)) if reply.accepted));
+    }
 
-    window.set_cursor_hittest(false).expect("Failed to set window to be passthrough.");
-    window.set_outer_position(LogicalPosition::new(-1000, -1000));
+    #[test]
+    fn state_reaches_overlay_over_loopback() {
+        let network = LoopbackNetwork::new();
+        let student = network.endpoint();
+        let student_id = student.local_id();
+        let mut tutor = network.endpoint();
+        let (tx, rx) = channel();
+        let mut server = ServerNetwork::new(&config(&[]), Box::new(student), tx).unwrap();
 
-    let pointer_image_bytes = include_bytes!("pointer.png");
-    let pointer_image = image::load_from_memory(pointer_image_bytes).expect("Failed to load pointer image from memory?? uh oh");
+        greet(&mut server, &mut tutor, student_id);
 
-    let context = softbuffer::Context::new(window.clone()).expect("Failed to create graphics context.");
-    let mut surface = Surface::new(&context, window.clone()).expect("Failed to create graphics surface.");
+        let position = MousePosition { x : 0.25, y : 0.75 };
+        let update = StateUpdate { sequence : 1, samples : vec![StateSample { timestamp : 10, state : UserState::Visible(position) }] };
+        tutor.send(student_id, SendMode::Unreliable, &encode(&UserPacket::State(update)));
+        for _ in 0..POLLS {
+            server.poll().unwrap();
+        }
+        let states : Vec<UserState> = rx.try_iter().filter_map(|packet| match packet.data {
+            UserData::State(sample) if packet.owner == tutor.local_id() => Some(sample.state),
+            _ => None,
+        }).collect();
+        // Positions are quantized on the wire.
+        assert!(matches!(states.as_slice(), [UserState::Visible(got)] if (got.x - position.x).abs() < 0.001 && (got.y - position.y).abs() < 0.001));
+    }
 
-    surface.resize(NonZeroU32::new(CURSOR_SIZE).unwrap(), NonZeroU32::new(CURSOR_SIZE).unwrap()).unwrap();
+    #[test]
+    fn upload_resumes_after_reconnecting() {
+        let network = LoopbackNetwork::new();
+        let student = network.endpoint();
+        let student_id = student.local_id();
+        let mut tutor = network.endpoint();
+        let (tx, _rx) = channel();
+        let mut server = ServerNetwork::new(&config(&[]), Box::new(student), tx).unwrap();
 
-    set_frame(&mut surface, &pointer_image, 0);
+        // Never a valid image, it only has to get halfway.
+        let bytes : Vec<u8> = (0..CHUNK_SIZE * 3).map(|index| index as u8).collect();
+        let manifest = manifest_for(AssetKind::CursorSheet, &bytes, 1);
+        greet(&mut server, &mut tutor, student_id);
+        exchange(&mut server, &mut tutor, student_id, &UserPacket::AssetManifest(manifest.clone()));
+        let first = chunks_from(manifest.hash, &bytes, 0).next().unwrap();
+        tutor.send(student_id, SendMode::Reliable, &encode(&UserPacket::AssetChunk(first)));
+        for _ in 0..POLLS {
+            server.poll().unwrap();
+        }
+        server.drop_peer(tutor.local_id(), "disconnected").unwrap();
 
-    UserWindow {
-        window,
-        surface,
-        state : Idle,
-        frame : 0,
-        image : pointer_image,
-        animation_set : UserAnimationStates::new()
+        // Coming back on a new connection, they're told to carry on from the first chunk.
+        let mut tutor = network.endpoint();
+        greet(&mut server, &mut tutor, student_id);
+        let status = exchange(&mut server, &mut tutor, student_id, &UserPacket::AssetManifest(manifest.clone()));
+        assert!(matches!(status, Some(UserPacket::AssetStatus(status)) if status.received as usize == CHUNK_SIZE));
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
+use crate::protocol::PROTOCOL_VERSION;
 
 pub const CURSOR_SIZE : u32 = 64;
+// Widest cursor sheet we take, animations can't point past it.
+pub const MAX_CURSOR_FRAMES : u32 = 1024;
 pub const APP_ID : u32 = 480; // TODO: Replace with a real steam ID
+pub const APP_VERSION : &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Serialize,Deserialize,PartialEq,Clone,Copy)]
 pub struct MousePosition {
@@ -19,8 +23,163 @@ pub enum UserState {
 #[derive(Serialize,Deserialize,PartialEq,Clone)]
 #[serde(tag = "type")]
 pub enum UserPacket {
-    State(UserState),
-    AnimationSet(UserAnimationStates)
+    State(StateUpdate),
+    Hello(Hello),
+    HelloReply(HelloReply),
+    AssetManifest(AssetManifest),
+    AssetChunk(AssetChunk),
+    AssetStatus(AssetStatus),
+    Annotation(Annotation),
+    Shape(ShapeUpdate),
+    Callout(Callout),
+    Heartbeat,
+    Goodbye,
+}
+
+// Sequence numbers let the student throw away updates that arrive after newer ones.
+// Each update carries a batch of samples so the student can replay the gesture at its original speed.
+#[derive(Serialize,Deserialize,PartialEq,Clone)]
+pub struct StateUpdate {
+    pub sequence : u32,
+    pub samples : Vec<StateSample>,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Clone)]
+pub struct StateSample {
+    // Milliseconds since the tutor connected.
+    pub timestamp : u32,
+    pub state : UserState,
+}
+
+// Sent by the tutor as soon as it connects, nothing else is acted on until the student accepts it.
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub struct Hello {
+    pub app_version : String,
+    pub protocol_version : u8,
+    pub name : String,
+    pub features : Features,
+    // The PIN or one-time token the student asked for, empty if they didn't give us one.
+    pub secret : String,
+    // Random and kept on the tutor's machine, so the student can tell it's them coming back. Empty from older builds.
+    pub install : String,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub struct HelloReply {
+    pub accepted : bool,
+    pub reason : String,
+    pub app_version : String,
+    pub protocol_version : u8,
+    pub features : Features,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone,Copy)]
+pub struct Features(pub u32);
+
+pub type AssetHash = [u8; 32];
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub struct AssetManifest {
+    pub hash : AssetHash,
+    pub kind : AssetKind,
+    pub total_size : u32,
+    pub frame_count : u32,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone,Copy)]
+pub enum AssetKind {
+    CursorSheet,
+    AnimationSet,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub struct AssetChunk {
+    pub hash : AssetHash,
+    pub offset : u32,
+    pub data : Vec<u8>,
+}
+
+// Sent by the student in reply to manifests and chunks.
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub struct AssetStatus {
+    pub hash : AssetHash,
+    pub received : u32,
+    pub progress : AssetProgress,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub enum AssetProgress {
+    Receiving,
+    Complete,
+    Failed(String),
+}
+
+// Ink the tutor leaves on the student's screen, sent reliably since it sticks around until it's cleared.
+#[derive(Serialize,Deserialize,PartialEq,Clone)]
+pub enum Annotation {
+    Begin {
+        stroke : u32,
+        style : StrokeStyle,
+        point : MousePosition,
+    },
+    Extend {
+        stroke : u32,
+        points : Vec<MousePosition>,
+    },
+    Clear,
+    // Steps back or forward through the tutor's own changes.
+    Undo,
+    Redo,
+    // The tutor let go, so the next change to the same shape is a separate step to undo.
+    Done,
+    // Wipes every tutor's ink, only honoured from the student's host tutor.
+    ClearAll,
+}
+
+// Shapes are placed whole, placing one again with the same id moves it.
+#[derive(Serialize,Deserialize,PartialEq,Clone)]
+pub enum ShapeUpdate {
+    Place(Shape),
+    // Takes callouts away too, they share ids with shapes.
+    Remove(u32),
+}
+
+// A short label on a plate, sent again with the same id as the tutor types it.
+#[derive(Serialize,Deserialize,PartialEq,Clone)]
+pub struct Callout {
+    pub id : u32,
+    // The top left corner of the plate.
+    pub position : MousePosition,
+    pub text : String,
+    // Pixels on the student's screen.
+    pub size : u8,
+    pub color : [u8; 3],
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Clone,Copy)]
+pub struct Shape {
+    pub id : u32,
+    pub kind : ShapeKind,
+    pub style : StrokeStyle,
+    // Where the drag started and ended, the arrow points at `to`.
+    pub from : MousePosition,
+    pub to : MousePosition,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone,Copy)]
+pub enum ShapeKind {
+    Arrow,
+    Rectangle,
+    Ellipse,
+    // A see-through box over something worth looking at.
+    Highlight,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone,Copy)]
+pub struct StrokeStyle {
+    pub color : [u8; 3],
+    // In pixels on the student's screen.
+    pub width : u8,
 }
 
 #[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
@@ -31,7 +190,7 @@ pub struct UserAnimationStates {
 }
 #[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
 pub struct Animation {
-    frames : Vec<Frame>,
+    pub frames : Vec<Frame>,
 }
 #[derive(Serialize,Deserialize,PartialEq,Debug,Clone,Copy)]
 pub struct Frame {
@@ -39,6 +198,23 @@ pub struct Frame {
     pub duration : f32,
 }
 
+impl UserState {
+    pub fn position(&self) -> Option<MousePosition> {
+        match self {
+            UserState::Idle => None,
+            UserState::Visible(position) | UserState::Flashing(position) => Some(*position),
+        }
+    }
+    // Same state somewhere else, idle stays idle.
+    pub fn with_position(&self, position : MousePosition) -> UserState {
+        match self {
+            UserState::Idle => UserState::Idle,
+            UserState::Visible(_) => UserState::Visible(position),
+            UserState::Flashing(_) => UserState::Flashing(position),
+        }
+    }
+}
+
 impl UserAnimationStates {
     pub fn new() -> UserAnimationStates {
         UserAnimationStates {
@@ -79,6 +255,69 @@ impl Animation {
     }
 }
 
+impl Features {
+    pub const CUSTOM_CURSORS : Features = Features(1 << 0);
+    pub const CUSTOM_ANIMATIONS : Features = Features(1 << 1);
+    pub const ANNOTATIONS : Features = Features(1 << 2);
+    pub const UNDO : Features = Features(1 << 3);
+
+    pub fn supported() -> Features {
+        Features(Features::CUSTOM_CURSORS.0 | Features::CUSTOM_ANIMATIONS.0 | Features::ANNOTATIONS.0 | Features::UNDO.0)
+    }
+    pub fn contains(&self, other : Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl StateUpdate {
+    // Wrapping comparison, so a long session rolling the counter over doesn't freeze the cursor.
+    pub fn is_newer_than(&self, sequence : u32) -> bool {
+        let difference = self.sequence.wrapping_sub(sequence);
+        difference != 0 && difference < u32::MAX / 2
+    }
+}
+
+impl AssetKind {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            AssetKind::CursorSheet => "cursor",
+            AssetKind::AnimationSet => "animations",
+        }
+    }
+}
+
+impl Hello {
+    pub fn new(name : String, secret : String, install : String) -> Hello {
+        Hello {
+            app_version : APP_VERSION.to_string(),
+            protocol_version : PROTOCOL_VERSION,
+            name,
+            features : Features::supported(),
+            secret,
+            install,
+        }
+    }
+}
+
+impl HelloReply {
+    pub fn accept() -> HelloReply {
+        HelloReply {
+            accepted : true,
+            reason : String::new(),
+            app_version : APP_VERSION.to_string(),
+            protocol_version : PROTOCOL_VERSION,
+            features : Features::supported(),
+        }
+    }
+    pub fn reject(reason : String) -> HelloReply {
+        HelloReply {
+            accepted : false,
+            reason,
+            .. HelloReply::accept()
+        }
+    }
+}
+
 impl Frame {
     pub fn new() -> Frame {
         Frame { index: 0, duration: 1.0, }
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use crate::shared::{MousePosition, UserState};
+
+// Samples kept behind the playback point, on top of the delay, in milliseconds of the tutor's clock.
+const HISTORY : f64 = 100.0;
+// Only there so a tutor flooding us with samples can't grow the buffer forever, the history above is what normally trims it.
+const MAX_SAMPLES : usize = 4096;
+// How fast the clock offset is let back up when nothing beats it, in milliseconds per millisecond.
+const OFFSET_CREEP : f64 = 0.002;
+
+#[derive(Clone, Copy)]
+pub struct Smoothing {
+    pub latency : Duration,
+    pub max_extrapolation : Duration,
+}
+
+#[derive(Clone)]
+pub struct Sample {
+    // Milliseconds on the tutor's clock.
+    pub timestamp : u32,
+    pub received : Instant,
+    pub state : UserState,
+}
+
+// Holds pointer states back by a small delay and plays them out at the pace the tutor sent them,
+// instead of whenever the network gets around to delivering them.
+pub struct JitterBuffer {
+    settings : Smoothing,
+    epoch : Instant,
+    samples : VecDeque<(f64, UserState)>,
+    // Smallest gap seen between the tutor's clock and ours, the packet that took it had the least delay.
+    // It slowly creeps back up so one lucky packet or the clocks drifting apart don't throw it off for good.
+    offset : Option<f64>,
+    offset_updated : f64,
+}
+
+impl JitterBuffer {
+    pub fn new(settings : Smoothing) -> JitterBuffer {
+        JitterBuffer {
+            settings,
+            epoch : Instant::now(),
+            samples : VecDeque::new(),
+            offset : None,
+            offset_updated : 0.0,
+        }
+    }
+
+    pub fn push(&mut self, sample : Sample) {
+        let timestamp = sample.timestamp as f64;
+        // Stale updates are dropped before they get here, so going backwards means the tutor reconnected.
+        if let Some((last, _)) = self.samples.back() {
+            if timestamp < *last {
+                self.samples.clear();
+                self.offset = None;
+            }
+        }
+        let received = self.millis(sample.received);
+        let offset = received - timestamp;
+        self.offset = Some(match self.offset {
+            Some(current) => (current + (received - self.offset_updated).max(0.0) * OFFSET_CREEP).min(offset),
+            None => offset,
+        });
+        self.offset_updated = received;
+        self.samples.push_back((timestamp, sample.state));
+        // Anything further back than the delay is long past being played out, the sample just before that is kept to slide from.
+        let horizon = timestamp - self.settings.latency.as_secs_f64() * 1000.0 - HISTORY;
+        while self.samples.len() > 2 && self.samples[1].0 < horizon {
+            self.samples.pop_front();
+        }
+        while self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    // What the pointer should look like right now, None until something has arrived.
+    pub fn sample(&mut self, now : Instant) -> Option<UserState> {
+        let offset = self.offset?;
+        let playback = self.millis(now) - offset - self.settings.latency.as_secs_f64() * 1000.0;
+        // Keep the two samples either side of the playback time, or the last two if we've run out.
+        while self.samples.len() > 2 && self.samples[1].0 <= playback {
+            self.samples.pop_front();
+        }
+        let (from_time, from) = self.samples.front()?;
+        match self.samples.get(1) {
+            Some((to_time, to)) if *to_time > playback => {
+                let amount = ((playback - from_time) / (to_time - from_time)).clamp(0.0, 1.0);
+                Some(interpolate(from, to, amount as f32))
+            }
+            Some((to_time, to)) => {
+                let ahead = (playback - to_time).min(self.settings.max_extrapolation.as_secs_f64() * 1000.0);
+                Some(extrapolate(*from_time, from, *to_time, to, ahead))
+            }
+            None => Some(from.clone()),
+        }
+    }
+
+    fn millis(&self, instant : Instant) -> f64 {
+        instant.saturating_duration_since(self.epoch).as_secs_f64() * 1000.0
+    }
+}
+
+// The state itself only changes once we reach the next sample, only the position slides.
+fn interpolate(from : &UserState, to : &UserState, amount : f32) -> UserState {
+    match (from.position(), to.position()) {
+        (Some(a), Some(b)) => from.with_position(MousePosition {
+            x : a.x + (b.x - a.x) * amount,
+            y : a.y + (b.y - a.y) * amount,
+        }),
+        _ => from.clone(),
+    }
+}
+
+fn extrapolate(from_time : f64, from : &UserState, to_time : f64, to : &UserState, ahead : f64) -> UserState {
+    let elapsed = to_time - from_time;
+    if ahead <= 0.0 || elapsed <= 0.0 {
+        return to.clone();
+    }
+    match (from.position(), to.position()) {
+        (Some(a), Some(b)) => {
+            let scale = (ahead / elapsed) as f32;
+            to.with_position(MousePosition {
+                x : (b.x + (b.x - a.x) * scale).clamp(0.0, 1.0),
+                y : (b.y + (b.y - a.y) * scale).clamp(0.0, 1.0),
+            })
+        }
+        _ => to.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(latency : u64) -> Smoothing {
+        Smoothing {
+            latency : Duration::from_millis(latency),
+            max_extrapolation : Duration::ZERO,
+        }
+    }
+
+    fn at(x : f32) -> UserState {
+        UserState::Visible(MousePosition { x, y : 0.5 })
+    }
+
+    fn x(state : Option<UserState>) -> f32 {
+        state.and_then(|state| state.position()).unwrap().x
+    }
+
+    #[test]
+    fn long_delays_keep_enough_samples() {
+        let mut buffer = JitterBuffer::new(settings(2000));
+        let start = buffer.epoch;
+        // Two seconds of 120 Hz samples, far more than a fixed 64 would hold.
+        for index in 0..240u32 {
+            let timestamp = index * 1000 / 120;
+            buffer.push(Sample { timestamp, received : start + Duration::from_millis(timestamp as u64), state : at(index as f32 / 240.0) });
+        }
+        // Playback has only just reached the first sample.
+        assert!(x(buffer.sample(start + Duration::from_millis(2000))) < 0.01);
+    }
+
+    #[test]
+    fn offset_creeps_back_up() {
+        let mut buffer = JitterBuffer::new(settings(0));
+        let start = buffer.epoch;
+        // One packet that got here suspiciously fast, then everything else 50 ms late.
+        buffer.push(Sample { timestamp : 0, received : start, state : at(0.0) });
+        for timestamp in (100..60_000).step_by(100) {
+            buffer.push(Sample { timestamp, received : start + Duration::from_millis(timestamp as u64 + 50), state : at(0.0) });
+        }
+        assert!((buffer.offset.unwrap() - 50.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use steamworks::SteamId;
+use crate::relay_protocol::format_code;
+
+mod direct;
+mod loopback;
+mod relay;
+mod steam;
+
+pub use direct::{resolve, DirectTransport};
+pub use loopback::LoopbackNetwork;
+pub use relay::{parse_join_code, RelayTransport};
+pub use steam::SteamTransport;
+
+#[derive(PartialEq,Eq,Hash,Clone,Copy,Debug)]
+pub enum PeerId {
+    Steam(SteamId),
+    Loopback(u64),
+    Ip(SocketAddr),
+    Relay(u32),
+}
+
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub enum SendMode {
+    Reliable,
+    Unreliable,
+}
+
+pub trait Transport : Send {
+    fn local_id(&self) -> PeerId;
+    fn peer_name(&self, peer : PeerId) -> String;
+    fn connect(&mut self, peer : PeerId) -> Result<(), Box<dyn Error>>;
+    // Returns the next peer that asked to open a session, after letting it in.
+    fn accept(&mut self) -> Option<PeerId>;
+    fn disconnect(&mut self, peer : PeerId);
+    fn send(&mut self, peer : PeerId, mode : SendMode, data : &[u8]) -> bool;
+    fn receive(&mut self) -> Option<(PeerId, Vec<u8>)>;
+    // A peer whose connection closed or broke on its own, so it can be let go without waiting for it to time out.
+    fn closed(&mut self) -> Option<PeerId> {
+        None
+    }
+}
+
+impl PeerId {
+    // Something that identifies the same person across sessions, if this kind of connection has one.
+    pub fn stable_key(&self) -> Option<String> {
+        match self {
+            PeerId::Steam(steam_id) => Some(format!("steam:{}", steam_id.raw())),
+            PeerId::Ip(addr) => Some(format!("ip:{}", addr.ip())),
+            PeerId::Loopback(_) | PeerId::Relay(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerId::Steam(steam_id) => write!(f, "{}", steam_id.raw()),
+            PeerId::Loopback(id) => write!(f, "loopback-{}", id),
+            PeerId::Ip(addr) => write!(f, "{}", addr),
+            PeerId::Relay(id) => write!(f, "{}", format_code(*id)),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::framing::{flush_pending, frame, take_frame, write_frame};
+use crate::transport::{PeerId, SendMode, Transport};
+
+// Reliable packets go over TCP as length prefixed frames. Unreliable packets go over UDP,
+// prefixed with a random token the client sends as its first TCP frame, so we can tell which
+// connection a datagram belongs to even when the UDP source port doesn't match.
+const TOKEN_SIZE : usize = 8;
+// A peer that takes none of what we send for this long is dropped, rather than holding everyone else up.
+const WRITE_TIMEOUT : Duration = Duration::from_secs(10);
+// How long closing down waits for the last frames to go out.
+const LINGER : Duration = Duration::from_secs(1);
+
+struct Connection {
+    stream : TcpStream,
+    buffer : Vec<u8>,
+    // Frames the socket didn't have room for yet, they go out as it drains.
+    outgoing : Vec<u8>,
+    stalled_since : Option<Instant>,
+    token : Option<u64>,
+    udp_addr : Option<SocketAddr>,
+}
+
+impl Connection {
+    fn new(stream : TcpStream, token : Option<u64>, udp_addr : Option<SocketAddr>) -> Connection {
+        Connection { stream, buffer : Vec::new(), outgoing : Vec::new(), stalled_since : None, token, udp_addr }
+    }
+
+    // False once the connection is broken or the peer has stopped reading.
+    fn flush(&mut self) -> bool {
+        let written = match flush_pending(&mut self.stream, &mut self.outgoing) {
+            Ok(written) => written,
+            Err(_) => return false,
+        };
+        if self.outgoing.is_empty() || written > 0 {
+            self.stalled_since = None;
+            return true;
+        }
+        self.stalled_since.get_or_insert_with(Instant::now).elapsed() < WRITE_TIMEOUT
+    }
+}
+
+pub struct DirectTransport {
+    local_addr : SocketAddr,
+    listener : Option<TcpListener>,
+    udp : Option<UdpSocket>,
+    connections : HashMap<PeerId, Connection>,
+    tokens : HashMap<u64, PeerId>,
+    session_requests : VecDeque<PeerId>,
+    closed : VecDeque<PeerId>,
+    token : u64,
+}
+
+impl DirectTransport {
+    pub fn new() -> DirectTransport {
+        DirectTransport {
+            local_addr : SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            listener : None,
+            udp : None,
+            connections : HashMap::new(),
+            tokens : HashMap::new(),
+            session_requests : VecDeque::new(),
+            closed : VecDeque::new(),
+            token : rand::random(),
+        }
+    }
+
+    pub fn listen(addr : &str) -> Result<DirectTransport, Box<dyn Error>> {
+        let listener = TcpListener::bind(resolve(addr)?)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let udp = UdpSocket::bind(local_addr)?;
+        udp.set_nonblocking(true)?;
+        let mut transport = DirectTransport::new();
+        transport.local_addr = local_addr;
+        transport.listener = Some(listener);
+        transport.udp = Some(udp);
+        Ok(transport)
+    }
+
+    fn read_frames(&mut self) -> Option<(PeerId, Vec<u8>)> {
+        let mut closed = Vec::new();
+        let mut received = None;
+        for (peer, connection) in self.connections.iter_mut() {
+            let mut buf = [0; 65536];
+            loop {
+                match connection.stream.read(&mut buf) {
+                    Ok(0) => {
+                        closed.push(*peer);
+                        break;
+                    }
+                    Ok(amt) => connection.buffer.extend_from_slice(&buf[..amt]),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => {
+                        closed.push(*peer);
+                        break;
+                    }
+                }
+            }
+            while let Some(frame) = take_frame(&mut connection.buffer) {
+                match frame {
+                    Err(_) => {
+                        println!("Dropping {}, it sent an oversized frame.", peer);
+                        closed.push(*peer);
+                        break;
+                    }
+                    Ok(frame) => {
+                        if self.listener.is_some() && connection.token.is_none() {
+                            if frame.len() != TOKEN_SIZE {
+                                closed.push(*peer);
+                                break;
+                            }
+                            let token = u64::from_le_bytes(frame.try_into().unwrap());
+                            connection.token = Some(token);
+                            self.tokens.insert(token, *peer);
+                            continue;
+                        }
+                        received = Some((*peer, frame));
+                        break;
+                    }
+                }
+            }
+            if received.is_some() {
+                break;
+            }
+        }
+        for peer in closed {
+            self.close(peer);
+        }
+        received
+    }
+
+    fn flush_all(&mut self) {
+        let stalled : Vec<PeerId> = self.connections.iter_mut()
+            .filter(|(_, connection)| !connection.outgoing.is_empty())
+            .filter_map(|(peer, connection)| (!connection.flush()).then_some(*peer))
+            .collect();
+        for peer in stalled {
+            println!("Dropping {}, it stopped taking what we send.", peer);
+            self.close(peer);
+        }
+    }
+
+    fn close(&mut self, peer : PeerId) {
+        if let Some(connection) = self.connections.remove(&peer) {
+            if let Some(token) = connection.token {
+                self.tokens.remove(&token);
+            }
+            self.closed.push_back(peer);
+        }
+    }
+
+    fn read_datagram(&mut self) -> Option<(PeerId, Vec<u8>)> {
+        let udp = self.udp.as_ref()?;
+        let mut buf = [0; 65536];
+        loop {
+            let (amt, from) = match udp.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
+                Err(_) => return None,
+            };
+            if self.listener.is_none() {
+                // We only ever talk to the one server as a client.
+                match self.connections.keys().next() {
+                    Some(peer) => return Some((*peer, buf[..amt].to_vec())),
+                    None => continue,
+                }
+            }
+            if amt < TOKEN_SIZE {
+                continue;
+            }
+            let token = u64::from_le_bytes(buf[..TOKEN_SIZE].try_into().unwrap());
+            let peer = match self.tokens.get(&token) {
+                Some(peer) => *peer,
+                None => continue,
+            };
+            if let Some(connection) = self.connections.get_mut(&peer) {
+                connection.udp_addr = Some(from);
+            }
+            return Some((peer, buf[TOKEN_SIZE..amt].to_vec()));
+        }
+    }
+}
+
+impl Transport for DirectTransport {
+    fn local_id(&self) -> PeerId {
+        if !self.local_addr.ip().is_unspecified() {
+            return PeerId::Ip(self.local_addr);
+        }
+        match guess_lan_ip(self.local_addr.is_ipv6()) {
+            Some(ip) => PeerId::Ip(SocketAddr::new(ip, self.local_addr.port())),
+            None => PeerId::Ip(self.local_addr),
+        }
+    }
+
+    fn peer_name(&self, peer : PeerId) -> String {
+        peer.to_string()
+    }
+
+    fn connect(&mut self, peer : PeerId) -> Result<(), Box<dyn Error>> {
+        let addr = match peer {
+            PeerId::Ip(addr) => addr,
+            _ => return Err(format!("Direct transport can't connect to {}", peer).into()),
+        };
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        write_frame(&mut stream, &self.token.to_le_bytes())?;
+        stream.set_nonblocking(true)?;
+        let unspecified = if addr.is_ipv6() { IpAddr::V6(Ipv6Addr::UNSPECIFIED) } else { IpAddr::V4(Ipv4Addr::UNSPECIFIED) };
+        let udp = UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+        udp.connect(addr)?;
+        udp.set_nonblocking(true)?;
+        self.local_addr = stream.local_addr()?;
+        self.udp = Some(udp);
+        self.connections.insert(peer, Connection::new(stream, Some(self.token), Some(addr)));
+        Ok(())
+    }
+
+    fn accept(&mut self) -> Option<PeerId> {
+        if let Some(listener) = &self.listener {
+            while let Ok((stream, addr)) = listener.accept() {
+                if stream.set_nonblocking(true).is_err() || stream.set_nodelay(true).is_err() {
+                    continue;
+                }
+                let peer = PeerId::Ip(addr);
+                self.connections.insert(peer, Connection::new(stream, None, None));
+                self.session_requests.push_back(peer);
+            }
+        }
+        self.session_requests.pop_front()
+    }
+
+    fn disconnect(&mut self, peer : PeerId) {
+        if let Some(mut connection) = self.connections.remove(&peer) {
+            // Whatever fits still goes out, a rejection should reach them before the connection closes.
+            connection.flush();
+            if let Some(token) = connection.token {
+                self.tokens.remove(&token);
+            }
+        }
+        self.session_requests.retain(|request| *request != peer);
+    }
+
+    fn send(&mut self, peer : PeerId, mode : SendMode, data : &[u8]) -> bool {
+        let connection = match self.connections.get_mut(&peer) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        if let (SendMode::Unreliable, Some(udp), Some(udp_addr)) = (mode, &self.udp, connection.udp_addr) {
+            let mut datagram = Vec::with_capacity(TOKEN_SIZE + data.len());
+            if self.listener.is_none() {
+                datagram.extend_from_slice(&self.token.to_le_bytes());
+            }
+            datagram.extend_from_slice(data);
+            return udp.send_to(&datagram, udp_addr).is_ok();
+        }
+        connection.outgoing.extend_from_slice(&frame(data));
+        if !connection.flush() {
+            println!("Dropping {}, it stopped taking what we send.", peer);
+            self.close(peer);
+            return false;
+        }
+        true
+    }
+
+    fn receive(&mut self) -> Option<(PeerId, Vec<u8>)> {
+        self.flush_all();
+        self.read_frames().or_else(|| self.read_datagram())
+    }
+
+    fn closed(&mut self) -> Option<PeerId> {
+        self.closed.pop_front()
+    }
+}
+
+impl Drop for DirectTransport {
+    // A goodbye is usually the last thing sent, give it a moment to get out.
+    fn drop(&mut self) {
+        let deadline = Instant::now() + LINGER;
+        for connection in self.connections.values_mut() {
+            while !connection.outgoing.is_empty() && Instant::now() < deadline && connection.flush() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+pub fn resolve(addr : &str) -> Result<SocketAddr, Box<dyn Error>> {
+    match addr.to_socket_addrs()?.next() {
+        Some(addr) => Ok(addr),
+        None => Err(format!("Couldn't resolve {}", addr).into()),
+    }
+}
+
+// Connecting a UDP socket doesn't send anything, it just makes the OS pick the interface it would route through.
+fn guess_lan_ip(ipv6 : bool) -> Option<IpAddr> {
+    let (bind, target) = if ipv6 { ("[::]:0", "[2001:4860:4860::8888]:80") } else { ("0.0.0.0:0", "8.8.8.8:80") };
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(target).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
@@ -0,0 +1,110 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use crate::transport::{PeerId, SendMode, Transport};
+
+enum LoopbackMessage {
+    Connect(u64),
+    Data(u64, Vec<u8>),
+}
+
+// An in-process "network" that hands out connected endpoints, so the whole
+// server/client pipeline can run without Steam.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    endpoints : Arc<Mutex<HashMap<u64, Sender<LoopbackMessage>>>>,
+    next_id : Arc<AtomicU64>,
+}
+
+pub struct LoopbackTransport {
+    id : u64,
+    network : LoopbackNetwork,
+    inbox : Receiver<LoopbackMessage>,
+    session_requests : VecDeque<u64>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> LoopbackNetwork {
+        LoopbackNetwork {
+            endpoints : Arc::new(Mutex::new(HashMap::new())),
+            next_id : Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    pub fn endpoint(&self) -> LoopbackTransport {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = channel();
+        self.endpoints.lock().unwrap().insert(id, tx);
+        LoopbackTransport {
+            id,
+            network : self.clone(),
+            inbox : rx,
+            session_requests : VecDeque::new(),
+        }
+    }
+
+    fn deliver(&self, to : u64, message : LoopbackMessage) -> bool {
+        match self.endpoints.lock().unwrap().get(&to) {
+            None => false,
+            Some(tx) => tx.send(message).is_ok(),
+        }
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn local_id(&self) -> PeerId {
+        PeerId::Loopback(self.id)
+    }
+
+    fn peer_name(&self, peer : PeerId) -> String {
+        peer.to_string()
+    }
+
+    fn connect(&mut self, peer : PeerId) -> Result<(), Box<dyn Error>> {
+        match peer {
+            PeerId::Loopback(id) => {
+                if self.network.deliver(id, LoopbackMessage::Connect(self.id)) {
+                    Ok(())
+                } else {
+                    Err(format!("No loopback endpoint {}", peer).into())
+                }
+            }
+            _ => Err(format!("Loopback transport can't connect to {}", peer).into()),
+        }
+    }
+
+    fn accept(&mut self) -> Option<PeerId> {
+        self.session_requests.pop_front().map(PeerId::Loopback)
+    }
+
+    fn disconnect(&mut self, peer : PeerId) {
+        if let PeerId::Loopback(id) = peer {
+            self.session_requests.retain(|request| *request != id);
+        }
+    }
+
+    fn send(&mut self, peer : PeerId, _mode : SendMode, data : &[u8]) -> bool {
+        match peer {
+            PeerId::Loopback(id) => self.network.deliver(id, LoopbackMessage::Data(self.id, data.to_vec())),
+            _ => false,
+        }
+    }
+
+    fn receive(&mut self) -> Option<(PeerId, Vec<u8>)> {
+        while let Ok(message) = self.inbox.try_recv() {
+            match message {
+                LoopbackMessage::Connect(from) => self.session_requests.push_back(from),
+                LoopbackMessage::Data(from, data) => return Some((PeerId::Loopback(from), data)),
+            }
+        }
+        None
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.network.endpoints.lock().unwrap().remove(&self.id);
+    }
+}
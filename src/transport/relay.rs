@@ -0,0 +1,176 @@
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::framing::{take_frame, write_frame};
+use crate::relay_protocol::{RelayMessage, parse_code};
+use crate::transport::{resolve, PeerId, SendMode, Transport};
+
+const RELAY_TIMEOUT : Duration = Duration::from_secs(10);
+
+// Everything goes through the relay's TCP connection, it doesn't forward UDP.
+pub struct RelayTransport {
+    stream : TcpStream,
+    buffer : Vec<u8>,
+    id : u32,
+    session_requests : VecDeque<PeerId>,
+    // Everyone we're linked to through the relay, they all count as gone if it goes.
+    peers : HashSet<u32>,
+    closed : VecDeque<PeerId>,
+    lost : bool,
+}
+
+impl RelayTransport {
+    pub fn connect_relay(addr : &str) -> Result<RelayTransport, Box<dyn Error>> {
+        let stream = TcpStream::connect(resolve(addr)?)?;
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        let mut transport = RelayTransport {
+            stream,
+            buffer : Vec::new(),
+            id : 0,
+            session_requests : VecDeque::new(),
+            peers : HashSet::new(),
+            closed : VecDeque::new(),
+            lost : false,
+        };
+        match transport.wait_for_reply()? {
+            RelayMessage::Welcome(id) => transport.id = id,
+            message => return Err(format!("Unexpected reply from relay: {:?}", message).into()),
+        }
+        Ok(transport)
+    }
+
+    // Makes us joinable by our code, this is what the student does.
+    pub fn register(&mut self) -> Result<(), Box<dyn Error>> {
+        write_frame(&mut self.stream, &RelayMessage::Register.encode())?;
+        match self.wait_for_reply()? {
+            RelayMessage::Registered(_) => Ok(()),
+            RelayMessage::Error(err) => Err(err.into()),
+            message => Err(format!("Unexpected reply from relay: {:?}", message).into()),
+        }
+    }
+
+    fn wait_for_reply(&mut self) -> Result<RelayMessage, Box<dyn Error>> {
+        let deadline = Instant::now() + RELAY_TIMEOUT;
+        loop {
+            if let Some(message) = self.read_message()? {
+                return Ok(message);
+            }
+            if Instant::now() > deadline {
+                return Err("Timed out waiting for the relay.".into());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Option<RelayMessage>, Box<dyn Error>> {
+        let mut buf = [0; 65536];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err("The relay closed the connection.".into()),
+                Ok(amt) => self.buffer.extend_from_slice(&buf[..amt]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        match take_frame(&mut self.buffer) {
+            None => Ok(None),
+            Some(Err(_)) => Err("The relay sent an oversized frame.".into()),
+            Some(Ok(frame)) => match RelayMessage::decode(&frame) {
+                Some(message) => Ok(Some(message)),
+                None => Err("The relay sent a message we don't understand.".into()),
+            },
+        }
+    }
+}
+
+impl Transport for RelayTransport {
+    fn local_id(&self) -> PeerId {
+        PeerId::Relay(self.id)
+    }
+
+    fn peer_name(&self, peer : PeerId) -> String {
+        peer.to_string()
+    }
+
+    fn connect(&mut self, peer : PeerId) -> Result<(), Box<dyn Error>> {
+        let id = match peer {
+            PeerId::Relay(id) => id,
+            _ => return Err(format!("Relay transport can't connect to {}", peer).into()),
+        };
+        write_frame(&mut self.stream, &RelayMessage::Join(id).encode())?;
+        match self.wait_for_reply()? {
+            RelayMessage::Joined(_) => {
+                self.peers.insert(id);
+                Ok(())
+            }
+            RelayMessage::Error(err) => Err(err.into()),
+            message => Err(format!("Unexpected reply from relay: {:?}", message).into()),
+        }
+    }
+
+    fn accept(&mut self) -> Option<PeerId> {
+        self.session_requests.pop_front()
+    }
+
+    fn disconnect(&mut self, peer : PeerId) {
+        if let PeerId::Relay(id) = peer {
+            if self.peers.remove(&id) && !self.lost {
+                let _ = write_frame(&mut self.stream, &RelayMessage::Leave(id).encode());
+            }
+        }
+        self.session_requests.retain(|request| *request != peer);
+    }
+
+    fn send(&mut self, peer : PeerId, _mode : SendMode, data : &[u8]) -> bool {
+        match peer {
+            PeerId::Relay(id) => write_frame(&mut self.stream, &RelayMessage::Data(id, data.to_vec()).encode()).is_ok(),
+            _ => false,
+        }
+    }
+
+    fn receive(&mut self) -> Option<(PeerId, Vec<u8>)> {
+        if self.lost {
+            return None;
+        }
+        loop {
+            match self.read_message() {
+                Ok(None) => return None,
+                Ok(Some(RelayMessage::PeerJoined(id))) => {
+                    self.peers.insert(id);
+                    self.session_requests.push_back(PeerId::Relay(id));
+                }
+                Ok(Some(RelayMessage::PeerLeft(id))) => {
+                    if self.peers.remove(&id) {
+                        self.closed.push_back(PeerId::Relay(id));
+                    }
+                    self.session_requests.retain(|request| *request != PeerId::Relay(id));
+                }
+                Ok(Some(RelayMessage::Data(id, data))) => return Some((PeerId::Relay(id), data)),
+                Ok(Some(RelayMessage::Error(err))) => println!("Relay error: {}", err),
+                Ok(Some(_)) => {}
+                Err(err) => {
+                    println!("Lost the connection to the relay: {}", err);
+                    self.lost = true;
+                    self.closed.extend(self.peers.drain().map(PeerId::Relay));
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn closed(&mut self) -> Option<PeerId> {
+        self.closed.pop_front()
+    }
+}
+
+pub fn parse_join_code(code : &str) -> Result<PeerId, Box<dyn Error>> {
+    match parse_code(code) {
+        Some(id) => Ok(PeerId::Relay(id)),
+        None => Err(format!("{} isn't a valid join code.", code).into()),
+    }
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use steamworks::{CallbackHandle, Client, P2PSessionRequest, SendType, SingleClient, SteamId};
+use crate::shared::APP_ID;
+use crate::transport::{PeerId, SendMode, Transport};
+
+pub struct SteamTransport {
+    client : Client,
+    single_client : SingleClient,
+    session_requests : Arc<Mutex<Vec<SteamId>>>,
+    _session_callback : CallbackHandle,
+}
+
+impl SteamTransport {
+    pub fn new() -> Result<SteamTransport, Box<dyn Error>> {
+        let (client, single_client) = Client::init_app(APP_ID)?;
+        let session_requests = Arc::new(Mutex::new(Vec::new()));
+        let session_requests_copy = session_requests.clone();
+        let session_callback = client.register_callback(move |session_request : P2PSessionRequest| {
+            session_requests_copy.lock().unwrap().push(session_request.remote);
+        });
+        Ok(SteamTransport {
+            client,
+            single_client,
+            session_requests,
+            _session_callback : session_callback,
+        })
+    }
+}
+
+impl Transport for SteamTransport {
+    fn local_id(&self) -> PeerId {
+        PeerId::Steam(self.client.user().steam_id())
+    }
+
+    fn peer_name(&self, peer : PeerId) -> String {
+        match peer {
+            PeerId::Steam(steam_id) => self.client.friends().get_friend(steam_id).name(),
+            _ => peer.to_string(),
+        }
+    }
+
+    fn connect(&mut self, peer : PeerId) -> Result<(), Box<dyn Error>> {
+        match peer {
+            // Steam opens the session on the first packet sent, nothing to do up front.
+            PeerId::Steam(_) => Ok(()),
+            _ => Err(format!("Steam transport can't connect to {}", peer).into()),
+        }
+    }
+
+    fn accept(&mut self) -> Option<PeerId> {
+        let steam_id = self.session_requests.lock().unwrap().pop()?;
+        self.client.networking().accept_p2p_session(steam_id);
+        Some(PeerId::Steam(steam_id))
+    }
+
+    fn disconnect(&mut self, peer : PeerId) {
+        if let PeerId::Steam(steam_id) = peer {
+            self.client.networking().close_p2p_session(steam_id);
+        }
+    }
+
+    fn send(&mut self, peer : PeerId, mode : SendMode, data : &[u8]) -> bool {
+        let steam_id = match peer {
+            PeerId::Steam(steam_id) => steam_id,
+            _ => return false,
+        };
+        let send_type = match mode {
+            SendMode::Reliable => SendType::Reliable,
+            SendMode::Unreliable => SendType::UnreliableNoDelay,
+        };
+        self.client.networking().send_p2p_packet(steam_id, send_type, data)
+    }
+
+    fn receive(&mut self) -> Option<(PeerId, Vec<u8>)> {
+        self.single_client.run_callbacks();
+        let networking = self.client.networking();
+        let size = networking.is_p2p_packet_available()?;
+        let mut buf = vec![0; size];
+        let (steam_id, amt) = networking.read_p2p_packet(&mut buf)?;
+        buf.truncate(amt);
+        Some((PeerId::Steam(steam_id), buf))
+    }
+}